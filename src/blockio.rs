@@ -3,34 +3,106 @@
  *  Licensed under the MIT License. See LICENSE in the project root for license information.
  *----------------------------------------------------------------------------------------*/
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use crc::{Crc, CRC_32_ISO_HDLC};
 use std::io::prelude::*;
 use std::{cmp, io};
 
 pub const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
 
-const BLOCK_MAX_SIZE: usize = 4096;
+// the block size Inno Setup writes
+pub const DEFAULT_BLOCK_SIZE: usize = 4096;
+
+// the largest block size read by default, so that logs written with
+// larger blocks than Inno Setup's can still be read back
+pub const MAX_BLOCK_SIZE: usize = 64 * 1024;
 
 pub struct BlockRead<'a> {
 	reader: &'a mut dyn Read,
-	buffer: [u8; BLOCK_MAX_SIZE],
+	buffer: Vec<u8>,
 	pos: usize,
 	left: usize,
+	consumed: u64,
+	check_crc: bool,
+	// number of blocks read so far, and the size of the largest one
+	blocks: usize,
+	largest_block: usize,
 }
 
 impl<'a> BlockRead<'a> {
 	pub fn new(reader: &'a mut dyn Read) -> BlockRead<'a> {
+		BlockRead::with_block_size(reader, MAX_BLOCK_SIZE)
+	}
+
+	/**
+	 * Like `new`, but accepts blocks of up to `block_size` bytes.
+	 */
+	pub fn with_block_size(reader: &'a mut dyn Read, block_size: usize) -> BlockRead<'a> {
 		BlockRead {
 			reader,
-			buffer: [0; BLOCK_MAX_SIZE],
+			buffer: vec![0; block_size],
 			pos: 0,
 			left: 0,
+			consumed: 0,
+			check_crc: true,
+			blocks: 0,
+			largest_block: 0,
+		}
+	}
+
+	/**
+	 * Like `new`, but doesn't check the crc32 of each block.
+	 */
+	pub fn lenient(reader: &'a mut dyn Read) -> BlockRead<'a> {
+		BlockRead {
+			check_crc: false,
+			..BlockRead::new(reader)
 		}
 	}
 
-	fn fill_buffer(&mut self) -> Result<(), io::Error> {
-		let size = self.reader.read_u32::<LittleEndian>()?;
+	/**
+	 * Number of bytes consumed so far from the decoded (unblocked) stream.
+	 */
+	pub fn position(&self) -> u64 {
+		self.consumed
+	}
+
+	/**
+	 * The block size the stream was written with, as far as can be told:
+	 * only when more than one block was read, the first ones were full.
+	 */
+	pub fn observed_block_size(&self) -> Option<usize> {
+		if self.blocks > 1 {
+			Some(self.largest_block)
+		} else {
+			None
+		}
+	}
+
+	/**
+	 * Reads the next block into the buffer. Returns `false`, rather than
+	 * an error, when the stream cleanly ends before a new block header.
+	 */
+	fn fill_buffer(&mut self) -> Result<bool, io::Error> {
+		let mut size = [0; 4];
+		let mut size_read: usize = 0;
+
+		while size_read < size.len() {
+			match self.reader.read(&mut size[size_read..]) {
+				Ok(0) if size_read == 0 => return Ok(false),
+				Ok(0) => {
+					return Err(io::Error::new(
+						io::ErrorKind::UnexpectedEof,
+						"Block header is truncated",
+					))
+				}
+				Ok(count) => size_read += count,
+				Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+				Err(err) => return Err(err),
+			}
+		}
+
+		let size = LittleEndian::read_u32(&size);
 		let not_size = self.reader.read_u32::<LittleEndian>()?;
 		let crc = self.reader.read_u32::<LittleEndian>()?;
 
@@ -41,7 +113,7 @@ impl<'a> BlockRead<'a> {
 			));
 		}
 
-		if size > BLOCK_MAX_SIZE as u32 {
+		if size > self.buffer.len() as u32 {
 			return Err(io::Error::new(
 				io::ErrorKind::InvalidData,
 				"Block header size is too large",
@@ -52,7 +124,7 @@ impl<'a> BlockRead<'a> {
 		let buffer = &mut self.buffer[..size];
 		self.reader.read_exact(buffer)?;
 
-		if CRC32.checksum(buffer) != crc {
+		if self.check_crc && CRC32.checksum(buffer) != crc {
 			return Err(io::Error::new(
 				io::ErrorKind::InvalidData,
 				"Block header crc32 check failed",
@@ -61,8 +133,10 @@ impl<'a> BlockRead<'a> {
 
 		self.pos = 0;
 		self.left = size;
+		self.blocks += 1;
+		self.largest_block = cmp::max(self.largest_block, size);
 
-		Ok(())
+		Ok(true)
 	}
 }
 
@@ -72,8 +146,8 @@ impl<'a> Read for BlockRead<'a> {
 		let mut size = buf.len();
 
 		while size > 0 {
-			if self.left == 0 {
-				self.fill_buffer()?;
+			if self.left == 0 && !self.fill_buffer()? {
+				break;
 			}
 
 			let count = cmp::min(size, self.left);
@@ -83,25 +157,26 @@ impl<'a> Read for BlockRead<'a> {
 			to.copy_from_slice(from);
 			self.pos += count;
 			self.left -= count;
+			self.consumed += count as u64;
 			bytes_read += count;
 			size -= count;
 		}
 
-		Ok(buf.len())
+		Ok(bytes_read)
 	}
 }
 
 pub struct BlockWrite<'a> {
 	writer: &'a mut dyn Write,
-	buffer: [u8; BLOCK_MAX_SIZE],
+	buffer: Vec<u8>,
 	pos: usize,
 }
 
 impl<'a> BlockWrite<'a> {
-	pub fn new(writer: &'a mut dyn Write) -> BlockWrite<'a> {
+	pub fn with_block_size(writer: &'a mut dyn Write, block_size: usize) -> BlockWrite<'a> {
 		BlockWrite {
 			writer,
-			buffer: [0; BLOCK_MAX_SIZE],
+			buffer: vec![0; block_size],
 			pos: 0,
 		}
 	}
@@ -131,7 +206,7 @@ impl<'a> Write for BlockWrite<'a> {
 		let mut size = buf.len();
 
 		while size > 0 {
-			let left = BLOCK_MAX_SIZE - self.pos;
+			let left = self.buffer.len() - self.pos;
 			let count = cmp::min(size, left);
 
 			{
@@ -145,7 +220,7 @@ impl<'a> Write for BlockWrite<'a> {
 			bytes_written += count;
 			size -= count;
 
-			if self.pos == BLOCK_MAX_SIZE {
+			if self.pos == self.buffer.len() {
 				self.flush_buffer()?;
 			}
 		}
@@ -158,3 +233,81 @@ impl<'a> Write for BlockWrite<'a> {
 		self.writer.flush()
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn write_blocks(data: &[u8], block_size: usize) -> Vec<u8> {
+		let mut blocks = vec![];
+
+		{
+			let mut writer = BlockWrite::with_block_size(&mut blocks, block_size);
+			writer.write_all(data).unwrap();
+			writer.flush().unwrap();
+		}
+
+		blocks
+	}
+
+	#[test]
+	fn block_boundaries_survive_a_round_trip() {
+		let data: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+
+		for &block_size in &[100, DEFAULT_BLOCK_SIZE, 8192, MAX_BLOCK_SIZE] {
+			let blocks = write_blocks(&data, block_size);
+			let mut input = &blocks[..];
+			let mut reader = BlockRead::new(&mut input);
+			let mut read = vec![];
+			reader.read_to_end(&mut read).unwrap();
+
+			assert_eq!(read, data);
+
+			// a single block doesn't tell its size
+			let observed = reader.observed_block_size();
+			if data.len() > block_size {
+				assert_eq!(observed, Some(block_size));
+				assert_eq!(write_blocks(&read, observed.unwrap()), blocks);
+			} else {
+				assert_eq!(observed, None);
+			}
+		}
+	}
+
+	#[test]
+	fn blocks_larger_than_the_buffer_are_rejected() {
+		let blocks = write_blocks(&[0; 8192], 8192);
+		let mut input = &blocks[..];
+		let mut reader = BlockRead::with_block_size(&mut input, DEFAULT_BLOCK_SIZE);
+
+		let err = reader.read_to_end(&mut vec![]).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn reads_past_the_last_block_are_short_then_empty() {
+		let blocks = write_blocks(&[7; 10], DEFAULT_BLOCK_SIZE);
+		let mut input = &blocks[..];
+		let mut reader = BlockRead::new(&mut input);
+		let mut buf = [0; 16];
+
+		assert_eq!(reader.read(&mut buf).unwrap(), 10);
+		assert_eq!(&buf[..10], &[7; 10]);
+		assert_eq!(reader.read(&mut buf).unwrap(), 0);
+		assert_eq!(reader.position(), 10);
+	}
+
+	#[test]
+	fn truncated_blocks_are_errors() {
+		let blocks = write_blocks(&[7; 10], DEFAULT_BLOCK_SIZE);
+
+		// within the block header, then within the block data
+		for &len in &[2, blocks.len() - 1] {
+			let mut input = &blocks[..len];
+			let mut reader = BlockRead::new(&mut input);
+
+			let err = reader.read_to_end(&mut vec![]).unwrap_err();
+			assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+		}
+	}
+}
@@ -3,35 +3,89 @@
  *  Licensed under the MIT License. See LICENSE in the project root for license information.
  *----------------------------------------------------------------------------------------*/
 
+use messages::t;
+use std::cell::{Cell, RefCell};
 use std::sync::mpsc::Sender;
-use std::{mem, ptr};
+use std::time::{Duration, Instant};
+use std::{cmp, mem, ptr};
 use strings::to_utf16;
+use taskbar::Taskbar;
 use windows_sys::core::PCWSTR;
-use windows_sys::Win32::Foundation::{BOOL, HWND, LPARAM, WPARAM};
+use windows_sys::Win32::Foundation::{BOOL, HWND, LPARAM, RECT, WPARAM};
 
 extern "system" {
 	pub fn ShutdownBlockReasonCreate(hWnd: HWND, pwszReason: PCWSTR) -> BOOL;
 	pub fn ShutdownBlockReasonDestroy(hWnd: HWND) -> BOOL;
 }
 
+// 0x8000 == WM_APP; wparam is the percentage
+const WM_TASKBAR_PROGRESS: u32 = 0x8000 + 1;
+const WM_TASKBAR_CLEAR: u32 = 0x8000 + 2;
+
+thread_local! {
+	// lives on the dialog's thread, as COM objects must stay in their apartment
+	static TASKBAR: RefCell<Option<Taskbar>> = const { RefCell::new(None) };
+}
+
 struct DialogData {
 	silent: bool,
 	tx: Sender<ProgressWindow>,
 	label: String,
 }
 
-unsafe extern "system" fn dlgproc(hwnd: HWND, msg: u32, _: WPARAM, l: LPARAM) -> isize {
+/**
+ * Where to put a `width` by `height` window to center it within `area`,
+ * or `None` if the area is empty, as the desktop rect can be over Remote
+ * Desktop or without a GPU.
+ */
+fn centered_position(area: &RECT, width: i32, height: i32) -> Option<(i32, i32)> {
+	if area.right <= area.left || area.bottom <= area.top {
+		return None;
+	}
+
+	Some((
+		area.left + (area.right - area.left) / 2 - width / 2,
+		area.top + (area.bottom - area.top) / 2 - height / 2,
+	))
+}
+
+/**
+ * The work area of the monitor `hwnd` is on, so the dialog gets centered
+ * where it was created at that monitor's DPI, rather than on the primary
+ * monitor. Falls back to the whole desktop.
+ */
+unsafe fn target_area(hwnd: HWND) -> RECT {
+	use windows_sys::Win32::Graphics::Gdi::{
+		GetMonitorInfoW, MonitorFromWindow, MONITORINFO, MONITOR_DEFAULTTONEAREST,
+	};
+	use windows_sys::Win32::UI::WindowsAndMessaging::{GetDesktopWindow, GetWindowRect};
+
+	let mut info: MONITORINFO = mem::zeroed();
+	info.cbSize = mem::size_of::<MONITORINFO>() as u32;
+
+	let monitor = MonitorFromWindow(hwnd, MONITOR_DEFAULTTONEAREST);
+
+	if monitor != 0 && GetMonitorInfoW(monitor, &mut info) != 0 {
+		return info.rcWork;
+	}
+
+	GetWindowRect(GetDesktopWindow(), &mut info.rcWork);
+	info.rcWork
+}
+
+unsafe extern "system" fn dlgproc(hwnd: HWND, msg: u32, w: WPARAM, l: LPARAM) -> isize {
 	use resources;
-	use windows_sys::Win32::Foundation::RECT;
 	use windows_sys::Win32::System::Threading::GetCurrentThreadId;
 	use windows_sys::Win32::UI::WindowsAndMessaging::{
-		EndDialog, GetDesktopWindow, GetWindowRect, SendDlgItemMessageW, SetDlgItemTextW,
-		SetWindowPos, HWND_TOPMOST, SW_HIDE, WM_DESTROY, WM_INITDIALOG, WM_USER,
+		EndDialog, GetWindowRect, SendDlgItemMessageW, SetDlgItemTextW, SetWindowPos, HWND_TOPMOST,
+		SWP_NOACTIVATE, SWP_NOZORDER, SW_HIDE, WM_DESTROY, WM_DPICHANGED, WM_INITDIALOG, WM_USER,
 	};
 
 	match msg {
 		WM_INITDIALOG => {
 			let data = &*(l as *const DialogData);
+			let mut off_center = false;
+
 			if !data.silent {
 				SendDlgItemMessageW(hwnd, resources::PROGRESS_SLIDER, WM_USER + 10, 1, 0);
 
@@ -50,47 +104,232 @@ unsafe extern "system" fn dlgproc(hwnd: HWND, msg: u32, _: WPARAM, l: LPARAM) ->
 				let width = rect.right - rect.left;
 				let height = rect.bottom - rect.top;
 
-				GetWindowRect(GetDesktopWindow(), &mut rect);
+				// leave the dialog where it is rather than moving it off-screen
+				match centered_position(&target_area(hwnd), width, height) {
+					Some((x, y)) => {
+						SetWindowPos(hwnd, HWND_TOPMOST, x, y, width, height, 0);
+					}
+					None => off_center = true,
+				}
 
-				SetWindowPos(
-					hwnd,
-					HWND_TOPMOST,
-					rect.right / 2 - width / 2,
-					rect.bottom / 2 - height / 2,
-					width,
-					height,
-					0,
-				);
+				// matches the marquee until there's real progress
+				TASKBAR.with(|taskbar| {
+					let created = Taskbar::new(hwnd);
+
+					if let Some(ref created) = created {
+						created.set_progress(None);
+					}
+
+					*taskbar.borrow_mut() = created;
+				});
 			} else {
 				EndDialog(hwnd, 0);
 			}
 
+			ShutdownBlockReasonCreate(hwnd, to_utf16(&t("updating")).as_ptr());
+
+			// only hand out the window once it is fully initialized; the
+			// thread's message queue exists by now, so a WM_QUIT posted
+			// before the dialog enters its modal loop is still picked up
 			data.tx
 				.send(ProgressWindow {
 					ui_thread_id: GetCurrentThreadId(),
+					hwnd,
+					created: Instant::now(),
+					off_center,
+					progress: Cell::new(None),
+					creation_error: None,
+					silent: data.silent,
 				})
 				.unwrap();
 
-			ShutdownBlockReasonCreate(hwnd, to_utf16("Visual Studio Code is updating...").as_ptr());
 			0
 		}
+		WM_DPICHANGED => {
+			// moved to a monitor with another DPI: the dialog manager scales
+			// the contents, but the window takes the size Windows suggests
+			let rect = &*(l as *const RECT);
+			SetWindowPos(
+				hwnd,
+				0,
+				rect.left,
+				rect.top,
+				rect.right - rect.left,
+				rect.bottom - rect.top,
+				SWP_NOZORDER | SWP_NOACTIVATE,
+			);
+			1
+		}
+		WM_TASKBAR_PROGRESS => {
+			TASKBAR.with(|taskbar| {
+				if let Some(ref taskbar) = *taskbar.borrow() {
+					taskbar.set_progress(Some(w as u8));
+				}
+			});
+			1
+		}
+		WM_TASKBAR_CLEAR => {
+			TASKBAR.with(|taskbar| {
+				if let Some(ref taskbar) = *taskbar.borrow() {
+					taskbar.clear();
+				}
+			});
+			1
+		}
 		WM_DESTROY => {
 			ShutdownBlockReasonDestroy(hwnd);
+
+			// release it before COM gets uninitialized
+			TASKBAR.with(|taskbar| taskbar.borrow_mut().take());
+			0
+		}
+		_ => {
+			TASKBAR.with(|taskbar| {
+				if let Some(ref taskbar) = *taskbar.borrow() {
+					if taskbar.is_button_created(msg) {
+						taskbar.apply();
+					}
+				}
+			});
 			0
 		}
-		_ => 0,
 	}
 }
 
 pub struct ProgressWindow {
 	ui_thread_id: u32,
+	hwnd: HWND,
+	created: Instant,
+	off_center: bool,
+	// the last percentage set, if the progress bar left marquee mode
+	progress: Cell<Option<u8>>,
+	// why the dialog couldn't be created, in which case there's no window
+	creation_error: Option<String>,
+	// silent dialogs end as soon as they're initialized, leaving no window
+	silent: bool,
 }
 
 impl ProgressWindow {
+	/**
+	 * Whether there's a live dialog to talk to. Without one, `hwnd` may
+	 * since have been reused by an unrelated window.
+	 */
+	fn has_window(&self) -> bool {
+		self.creation_error.is_none() && !self.silent
+	}
+
+	/**
+	 * Shows `percent` (capped at 100) on the progress bar, switching it out
+	 * of marquee mode the first time.
+	 */
+	pub fn set_progress(&self, percent: u8) {
+		use resources;
+		use windows_sys::Win32::UI::WindowsAndMessaging::{
+			GetDlgItem, GetWindowLongW, SendDlgItemMessageW, SendMessageW, SetWindowLongW,
+			GWL_STYLE, WM_USER,
+		};
+
+		let percent = cmp::min(percent, 100);
+
+		if !self.has_window() || self.progress.get() == Some(percent) {
+			return;
+		}
+
+		unsafe {
+			if self.progress.get().is_none() {
+				// 8 == PBS_MARQUEE, WM_USER + 10 == PBM_SETMARQUEE
+				let slider = GetDlgItem(self.hwnd, resources::PROGRESS_SLIDER);
+				SendDlgItemMessageW(self.hwnd, resources::PROGRESS_SLIDER, WM_USER + 10, 0, 0);
+				SetWindowLongW(slider, GWL_STYLE, GetWindowLongW(slider, GWL_STYLE) & !8);
+
+				// WM_USER + 1 == PBM_SETRANGE, from 0 to 100
+				SendDlgItemMessageW(
+					self.hwnd,
+					resources::PROGRESS_SLIDER,
+					WM_USER + 1,
+					0,
+					100 << 16,
+				);
+			}
+
+			// WM_USER + 2 == PBM_SETPOS
+			SendDlgItemMessageW(
+				self.hwnd,
+				resources::PROGRESS_SLIDER,
+				WM_USER + 2,
+				percent as usize,
+				0,
+			);
+
+			SendMessageW(self.hwnd, WM_TASKBAR_PROGRESS, percent as usize, 0);
+		}
+
+		self.progress.set(Some(percent));
+	}
+
+	/**
+	 * Shows `text` on the detail line below the progress bar, such as the
+	 * file being worked on, leaving the label above it alone.
+	 */
+	pub fn update_detail(&self, text: &str) {
+		use resources;
+		use windows_sys::Win32::UI::WindowsAndMessaging::SetDlgItemTextW;
+
+		if !self.has_window() {
+			return;
+		}
+
+		unsafe {
+			SetDlgItemTextW(
+				self.hwnd,
+				resources::PROGRESS_DETAIL,
+				to_utf16(text).as_ptr(),
+			);
+		}
+	}
+
+	/**
+	 * Whether the dialog couldn't be centered, because the desktop
+	 * reported an empty rect.
+	 */
+	pub fn off_center(&self) -> bool {
+		self.off_center
+	}
+
+	/**
+	 * Why the dialog couldn't be created, if it couldn't. The update then
+	 * carries on without one, and all other calls do nothing.
+	 */
+	pub fn creation_error(&self) -> Option<&str> {
+		self.creation_error.as_deref()
+	}
+
+	/**
+	 * Whether the update runs silently, in which case nobody may be there
+	 * to answer a message box.
+	 */
+	pub fn silent(&self) -> bool {
+		self.silent
+	}
+
+	/**
+	 * How long ago the dialog finished initializing.
+	 */
+	pub fn lifetime(&self) -> Duration {
+		self.created.elapsed()
+	}
+
 	pub fn exit(&self) {
-		use windows_sys::Win32::UI::WindowsAndMessaging::{PostThreadMessageW, WM_QUIT};
+		use windows_sys::Win32::UI::WindowsAndMessaging::{
+			PostThreadMessageW, SendMessageW, WM_QUIT,
+		};
+
+		if !self.has_window() {
+			return;
+		}
 
 		unsafe {
+			SendMessageW(self.hwnd, WM_TASKBAR_CLEAR, 0, 0);
 			PostThreadMessageW(self.ui_thread_id, WM_QUIT, 0, 0);
 		}
 	}
@@ -98,19 +337,132 @@ impl ProgressWindow {
 
 pub fn run_progress_window(silent: bool, tx: Sender<ProgressWindow>, label: String) {
 	use resources;
+	use util;
+	use windows_sys::Win32::System::Com::{
+		CoInitializeEx, CoUninitialize, COINIT_APARTMENTTHREADED,
+	};
 	use windows_sys::Win32::System::LibraryLoader::GetModuleHandleW;
 	use windows_sys::Win32::UI::WindowsAndMessaging::DialogBoxParamW;
 
 	let data = DialogData { silent, tx, label };
 
-	unsafe {
+	// only the taskbar progress needs COM, so failing here is fine
+	let com_initialized = unsafe { CoInitializeEx(ptr::null(), COINIT_APARTMENTTHREADED) } >= 0;
+
+	let result = unsafe {
 		DialogBoxParamW(
 			GetModuleHandleW(ptr::null_mut()),
 			resources::PROGRESS_DIALOG as PCWSTR,
 			mem::zeroed(),
 			Some(dlgproc),
 			(&data as *const DialogData) as LPARAM,
+		)
+	};
+
+	if com_initialized {
+		unsafe { CoUninitialize() };
+	}
+
+	// the dialog was never created, so WM_INITDIALOG didn't hand out a
+	// window: hand out a headless one instead, so the update can go on
+	if result == -1 {
+		let creation_error =
+			util::get_last_error_message().unwrap_or_else(|_| String::from("unknown error"));
+
+		send_headless_window(&data.tx, data.silent, creation_error);
+	}
+}
+
+/**
+ * Hands out a window without a dialog, which failed to be created because
+ * of `creation_error`.
+ */
+fn send_headless_window(tx: &Sender<ProgressWindow>, silent: bool, creation_error: String) {
+	// the receiver may be gone already, there's nobody to tell then
+	let _ = tx.send(ProgressWindow {
+		ui_thread_id: 0,
+		hwnd: 0,
+		created: Instant::now(),
+		off_center: false,
+		progress: Cell::new(None),
+		creation_error: Some(creation_error),
+		silent,
+	});
+}
+
+unsafe extern "system" fn failure_dlgproc(hwnd: HWND, msg: u32, w: WPARAM, l: LPARAM) -> isize {
+	use resources;
+	use windows_sys::Win32::UI::WindowsAndMessaging::{
+		EndDialog, SetDlgItemTextW, IDCANCEL, WM_COMMAND, WM_INITDIALOG,
+	};
+
+	match msg {
+		WM_INITDIALOG => {
+			let text = &*(l as *const String);
+			SetDlgItemTextW(hwnd, resources::FAILURE_TEXT, to_utf16(text).as_ptr());
+			SetDlgItemTextW(
+				hwnd,
+				resources::FAILURE_OPEN_LOG,
+				to_utf16(&t("open-log")).as_ptr(),
+			);
+			SetDlgItemTextW(hwnd, IDCANCEL, to_utf16(&t("close")).as_ptr());
+			1
+		}
+		WM_COMMAND => {
+			// the low word is the id of the button clicked
+			let id = (w & 0xffff) as i32;
+
+			if id == resources::FAILURE_OPEN_LOG || id == IDCANCEL {
+				EndDialog(hwnd, id as isize);
+				return 1;
+			}
+
+			0
+		}
+		_ => 0,
+	}
+}
+
+/**
+ * Tells the user the update failed with `text`, in a dialog which can open
+ * the log file at `log_path`. Falls back to a plain message box if the
+ * dialog can't be created.
+ */
+pub fn failure_dialog(text: &str, log_path: &str) {
+	use resources;
+	use windows_sys::Win32::System::LibraryLoader::GetModuleHandleW;
+	use windows_sys::Win32::UI::Shell::ShellExecuteW;
+	use windows_sys::Win32::UI::WindowsAndMessaging::{DialogBoxParamW, SW_SHOWNORMAL};
+
+	let text = String::from(text);
+
+	let result = unsafe {
+		DialogBoxParamW(
+			GetModuleHandleW(ptr::null_mut()),
+			resources::FAILURE_DIALOG as PCWSTR,
+			mem::zeroed(),
+			Some(failure_dlgproc),
+			(&text as *const String) as LPARAM,
+		)
+	};
+
+	if result == -1 {
+		message_box(
+			&format!("{}\n\n{}", text, log_path),
+			&t("product"),
+			MessageBoxType::Error,
 		);
+	} else if result == resources::FAILURE_OPEN_LOG as isize {
+		unsafe {
+			ShellExecuteW(
+				0,
+				to_utf16("open").as_ptr(),
+				to_utf16(log_path).as_ptr(),
+				ptr::null(),
+				ptr::null(),
+				SW_SHOWNORMAL,
+			);
+		}
 	}
 }
 
@@ -166,3 +518,70 @@ pub fn message_box(text: &str, caption: &str, mbtype: MessageBoxType) -> Message
 		_ => MessageBoxResult::Unknown,
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::mpsc;
+	use std::thread;
+
+	fn rect(left: i32, top: i32, right: i32, bottom: i32) -> RECT {
+		RECT {
+			left,
+			top,
+			right,
+			bottom,
+		}
+	}
+
+	#[test]
+	fn empty_areas_have_no_centered_position() {
+		assert_eq!(centered_position(&rect(0, 0, 0, 0), 400, 100), None);
+		assert_eq!(centered_position(&rect(0, 0, 1920, 0), 400, 100), None);
+		assert_eq!(centered_position(&rect(100, 0, 50, 1080), 400, 100), None);
+
+		// a secondary monitor left of the primary one
+		assert_eq!(
+			centered_position(&rect(-1920, 0, 0, 1080), 400, 100),
+			Some((-1160, 490))
+		);
+	}
+
+	#[test]
+	fn progress_window_is_handed_out_before_it_exits() {
+		let (tx, rx) = mpsc::channel();
+		let ui_thread = thread::spawn(move || {
+			run_progress_window(true, tx, String::from("Updating"));
+		});
+
+		// a window comes back whether or not the dialog could be created,
+		// and calls on it are fine either way
+		let window = rx.recv().unwrap();
+		assert!(window.silent());
+
+		window.set_progress(50);
+		window.update_detail("Code.exe");
+		window.exit();
+
+		ui_thread.join().unwrap();
+	}
+
+	#[test]
+	fn failed_dialogs_still_hand_out_a_window() {
+		let (tx, rx) = mpsc::channel();
+		send_headless_window(&tx, false, String::from("no resources"));
+
+		let window = rx.recv().unwrap();
+		assert_eq!(window.creation_error(), Some("no resources"));
+		assert!(!window.silent());
+
+		// there's no dialog to update or close
+		window.set_progress(50);
+		window.update_detail("Code.exe");
+		window.exit();
+
+		// nor anyone to tell, once the receiver is gone
+		drop(rx);
+		send_headless_window(&tx, false, String::from("no resources"));
+	}
+}
@@ -3,27 +3,107 @@
  *  Licensed under the MIT License. See LICENSE in the project root for license information.
  *----------------------------------------------------------------------------------------*/
 
+use std::cell::Cell;
 use std::ffi::c_void;
 use std::path::Path;
-use std::{error, io, ptr};
+use std::{error, fmt, io, ptr};
 use strings::to_u16s;
 use util;
 use windows_sys::Win32::Foundation::HANDLE;
 
-pub struct FileHandle(HANDLE);
+/**
+ * Why a file handle couldn't be opened. Sharing and lock violations come
+ * from other processes holding the file and usually go away, the rest
+ * won't be fixed by trying again.
+ */
+#[derive(Debug)]
+pub enum FileHandleError {
+	SharingViolation(String),
+	LockViolation(String),
+	FileNotFound(String),
+	AccessDenied(String),
+	Other(String),
+}
+
+impl FileHandleError {
+	pub fn is_retryable(&self) -> bool {
+		matches!(
+			*self,
+			FileHandleError::SharingViolation(_) | FileHandleError::LockViolation(_)
+		)
+	}
+
+	/**
+	 * Whether `err` is worth retrying; errors other than a
+	 * `FileHandleError` are assumed to be.
+	 */
+	pub fn is_retryable_error(err: &(dyn error::Error + 'static)) -> bool {
+		err.downcast_ref::<FileHandleError>()
+			.is_none_or(|err| err.is_retryable())
+	}
+}
+
+impl fmt::Display for FileHandleError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let message = match *self {
+			FileHandleError::SharingViolation(ref message)
+			| FileHandleError::LockViolation(ref message)
+			| FileHandleError::FileNotFound(ref message)
+			| FileHandleError::AccessDenied(ref message)
+			| FileHandleError::Other(ref message) => message,
+		};
+
+		write!(f, "Failed to create file handle: {}", message)
+	}
+}
+
+impl From<FileHandleError> for io::Error {
+	fn from(err: FileHandleError) -> io::Error {
+		let kind = match err {
+			FileHandleError::FileNotFound(_) => io::ErrorKind::NotFound,
+			FileHandleError::AccessDenied(_) => io::ErrorKind::PermissionDenied,
+			_ => io::ErrorKind::Other,
+		};
+
+		io::Error::new(kind, err)
+	}
+}
+
+impl error::Error for FileHandleError {
+	fn description(&self) -> &str {
+		"FileHandleError"
+	}
+
+	fn cause(&self) -> Option<&dyn error::Error> {
+		None
+	}
+}
+
+// the flag records whether the handle was closed already
+pub struct FileHandle(HANDLE, Cell<bool>);
 
 impl FileHandle {
+	/**
+	 * Opens `path` for deletion. Other processes may keep reading or writing
+	 * it, so files which are merely open elsewhere can still be deleted;
+	 * files which can't, such as running executables, are refused by
+	 * `mark_for_deletion` instead.
+	 */
 	pub fn new(path: &Path) -> Result<FileHandle, Box<dyn error::Error>> {
-		use windows_sys::Win32::Foundation::INVALID_HANDLE_VALUE;
+		use windows_sys::Win32::Foundation::{
+			GetLastError, ERROR_ACCESS_DENIED, ERROR_FILE_NOT_FOUND, ERROR_LOCK_VIOLATION,
+			ERROR_PATH_NOT_FOUND, ERROR_SHARING_VIOLATION, INVALID_HANDLE_VALUE,
+		};
 		use windows_sys::Win32::Storage::FileSystem::{
-			CreateFileW, DELETE, FILE_ATTRIBUTE_NORMAL, OPEN_EXISTING,
+			CreateFileW, DELETE, FILE_ATTRIBUTE_NORMAL, FILE_SHARE_DELETE, FILE_SHARE_READ,
+			FILE_SHARE_WRITE, OPEN_EXISTING,
 		};
 
 		unsafe {
 			let handle = CreateFileW(
 				to_u16s(path.as_os_str()).as_ptr(),
 				DELETE,
-				0,
+				FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
 				ptr::null_mut(),
 				OPEN_EXISTING,
 				FILE_ATTRIBUTE_NORMAL,
@@ -31,21 +111,83 @@ impl FileHandle {
 			);
 
 			if handle == INVALID_HANDLE_VALUE {
-				return Err(io::Error::new(
-					io::ErrorKind::Other,
-					format!(
-						"Failed to create file handle: {}",
-						util::get_last_error_message()?
-					),
-				)
+				// read the code first, getting the message may overwrite it
+				let code = GetLastError();
+				let message = util::get_last_error_message()?;
+
+				return Err(match code {
+					ERROR_SHARING_VIOLATION => FileHandleError::SharingViolation(message),
+					ERROR_LOCK_VIOLATION => FileHandleError::LockViolation(message),
+					ERROR_FILE_NOT_FOUND | ERROR_PATH_NOT_FOUND => {
+						FileHandleError::FileNotFound(message)
+					}
+					ERROR_ACCESS_DENIED => FileHandleError::AccessDenied(message),
+					_ => FileHandleError::Other(message),
+				}
 				.into());
 			}
 
-			Ok(FileHandle(handle))
+			Ok(FileHandle(handle, Cell::new(false)))
+		}
+	}
+
+	/**
+	 * Whether the current user may delete `path`, probed by opening it for
+	 * deletion without marking it. Sharing is wide open, so only access
+	 * rights, not other processes' handles, affect the answer. A path which
+	 * doesn't exist counts as deletable.
+	 */
+	pub fn can_delete(path: &Path) -> Result<bool, Box<dyn error::Error>> {
+		use windows_sys::Win32::Foundation::{
+			CloseHandle, GetLastError, ERROR_ACCESS_DENIED, ERROR_FILE_NOT_FOUND,
+			ERROR_PATH_NOT_FOUND, INVALID_HANDLE_VALUE,
+		};
+		use windows_sys::Win32::Storage::FileSystem::{
+			CreateFileW, DELETE, FILE_FLAG_BACKUP_SEMANTICS, FILE_SHARE_DELETE, FILE_SHARE_READ,
+			FILE_SHARE_WRITE, OPEN_EXISTING,
+		};
+
+		unsafe {
+			let handle = CreateFileW(
+				to_u16s(path.as_os_str()).as_ptr(),
+				DELETE,
+				FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+				ptr::null_mut(),
+				OPEN_EXISTING,
+				// needed to open directories
+				FILE_FLAG_BACKUP_SEMANTICS,
+				std::mem::zeroed(),
+			);
+
+			if handle != INVALID_HANDLE_VALUE {
+				CloseHandle(handle);
+				return Ok(true);
+			}
+
+			match GetLastError() {
+				ERROR_ACCESS_DENIED => Ok(false),
+				ERROR_FILE_NOT_FOUND | ERROR_PATH_NOT_FOUND => Ok(true),
+				_ => Err(io::Error::other(format!(
+					"Failed to probe file for deletion: {}",
+					util::get_last_error_message()?
+				))
+				.into()),
+			}
 		}
 	}
 
 	pub fn mark_for_deletion(&self) -> Result<(), Box<dyn error::Error>> {
+		self.set_delete_disposition(true)
+	}
+
+	/**
+	 * Takes back `mark_for_deletion`, as long as the handle is still open.
+	 */
+	pub fn unmark_for_deletion(&self) -> Result<(), Box<dyn error::Error>> {
+		self.set_delete_disposition(false)
+	}
+
+	fn set_delete_disposition(&self, delete: bool) -> Result<(), Box<dyn error::Error>> {
 		use std::mem;
 		use windows_sys::Win32::Foundation::BOOLEAN;
 		use windows_sys::Win32::Storage::FileSystem::{
@@ -54,7 +196,7 @@ impl FileHandle {
 
 		unsafe {
 			let mut info = FILE_DISPOSITION_INFO {
-				DeleteFile: 1 as BOOLEAN,
+				DeleteFile: delete as BOOLEAN,
 			};
 			let result = SetFileInformationByHandle(
 				self.0,
@@ -63,11 +205,12 @@ impl FileHandle {
 				mem::size_of::<FILE_DISPOSITION_INFO>() as u32,
 			);
 
-			if result.is_negative() {
+			if result == 0 {
 				return Err(io::Error::new(
 					io::ErrorKind::Other,
 					format!(
-						"Failed to mark file for deletion: {}",
+						"Failed to {} file for deletion: {}",
+						if delete { "mark" } else { "unmark" },
 						util::get_last_error_message()?
 					),
 				)
@@ -81,8 +224,12 @@ impl FileHandle {
 	pub fn close(&self) -> Result<(), Box<dyn error::Error>> {
 		use windows_sys::Win32::Foundation::CloseHandle;
 
+		if self.1.get() {
+			return Ok(());
+		}
+
 		unsafe {
-			if CloseHandle(self.0).is_negative() {
+			if CloseHandle(self.0) == 0 {
 				return Err(io::Error::new(
 					io::ErrorKind::Other,
 					format!(
@@ -94,6 +241,55 @@ impl FileHandle {
 			}
 		}
 
+		// only once it is really closed, so that a retry tries again
+		self.1.set(true);
 		Ok(())
 	}
 }
+
+impl Drop for FileHandle {
+	fn drop(&mut self) {
+		use windows_sys::Win32::Foundation::CloseHandle;
+
+		if !self.1.replace(true) {
+			unsafe {
+				CloseHandle(self.0);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{env, fs};
+
+	#[test]
+	fn read_shared_files_can_be_marked_for_deletion() {
+		let dir = env::temp_dir().join(format!("inno-updater-read-shared-{}", std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+
+		let path = dir.join("main.js");
+		fs::write(&path, b"read elsewhere").unwrap();
+
+		// std shares reads, writes and deletes, as most readers do
+		let reader = fs::File::open(&path).unwrap();
+
+		let handle = FileHandle::new(&path).unwrap();
+		handle.mark_for_deletion().unwrap();
+		handle.close().unwrap();
+
+		// the delete stays pending until the reader lets go too
+		let removed_while_read = fs::remove_dir(&dir).is_ok();
+		drop(reader);
+
+		let left = path.exists();
+		let removed = fs::remove_dir(&dir);
+		let _ = fs::remove_dir_all(&dir);
+
+		assert!(!removed_while_read);
+		assert!(!left);
+		removed.unwrap();
+	}
+}
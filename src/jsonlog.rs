@@ -0,0 +1,71 @@
+/*-----------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *----------------------------------------------------------------------------------------*/
+
+use slog::{Drain, OwnedKVList, Record, KV};
+use std::cell::RefCell;
+use std::io::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fmt, io};
+use strings::to_json_string;
+
+/**
+ * A drain writing each record as a JSON object on its own line, with its
+ * timestamp (milliseconds since the epoch), level, message and key/value
+ * pairs.
+ */
+pub struct JsonDrain<W: Write> {
+	writer: RefCell<W>,
+}
+
+impl<W: Write> JsonDrain<W> {
+	pub fn new(writer: W) -> JsonDrain<W> {
+		JsonDrain {
+			writer: RefCell::new(writer),
+		}
+	}
+}
+
+struct FieldSerializer(String);
+
+impl slog::Serializer for FieldSerializer {
+	fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+		self.0.push_str(&format!(
+			", {}: {}",
+			to_json_string(key),
+			to_json_string(&fmt::format(*val))
+		));
+
+		Ok(())
+	}
+}
+
+impl<W: Write> Drain for JsonDrain<W> {
+	type Ok = ();
+	type Err = io::Error;
+
+	fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), io::Error> {
+		let ts = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map(|d| d.as_millis())
+			.unwrap_or(0);
+
+		let mut fields = FieldSerializer(String::new());
+		record.kv().serialize(record, &mut fields)?;
+		values.serialize(record, &mut fields)?;
+
+		let mut writer = self.writer.borrow_mut();
+
+		writeln!(
+			writer,
+			"{{\"ts\": {}, \"level\": {}, \"msg\": {}{}}}",
+			ts,
+			to_json_string(record.level().as_str()),
+			to_json_string(&fmt::format(*record.msg())),
+			fields.0
+		)?;
+
+		writer.flush()
+	}
+}
@@ -0,0 +1,3204 @@
+/*-----------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *----------------------------------------------------------------------------------------*/
+
+// rustfmt indents with hard tabs, doc comments inside impls included
+#![allow(clippy::tabs_in_doc_comments)]
+
+extern crate byteorder;
+extern crate crc;
+#[macro_use]
+extern crate slog;
+extern crate windows_sys;
+
+mod blockio;
+pub mod gui;
+mod handle;
+pub mod jsonlog;
+pub mod locks;
+pub mod messages;
+pub mod model;
+pub mod process;
+mod resources;
+mod strings;
+mod taskbar;
+pub mod util;
+
+use handle::{FileHandle, FileHandleError};
+use messages::t;
+use model::{FileRec, Header};
+use std::collections::LinkedList;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+use std::{error, fmt, fs, io, thread};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// records along with the offsets they start at in the log's data
+type OffsetRecs = Vec<(u64, FileRec)>;
+
+fn read_file(path: &Path) -> Result<(Header, Vec<FileRec>), Box<dyn error::Error>> {
+	let (header, recs, _) = read_file_with_block_size(path)?;
+	Ok((header, recs))
+}
+
+/**
+ * Reads the uninstall log at `path`, failing if the file is shorter than
+ * its header's end offset: the blocks present may all pass their CRC
+ * checks while later ones are missing, as with a truncated download.
+ */
+fn read_log(path: &Path) -> Result<(Header, OffsetRecs, usize), Box<dyn error::Error>> {
+	let input_file = fs::File::open(path)?;
+	let len = input_file.metadata()?.len();
+	let (header, recs, block_size) = read_from_reader_with_offsets(io::BufReader::new(input_file))?;
+
+	check_not_truncated(len, &header)?;
+
+	Ok((header, recs, block_size))
+}
+
+fn check_not_truncated(len: u64, header: &Header) -> Result<(), io::Error> {
+	let end_offset = header.end_offset as u64;
+
+	if len < end_offset {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!(
+				"File is {} bytes shorter than end offset {}",
+				end_offset - len,
+				end_offset
+			),
+		));
+	}
+
+	Ok(())
+}
+
+/**
+ * Reads the file recs following a header one at a time, so that going
+ * through a huge uninstall log doesn't hold all of it in memory. Stops
+ * after the first error.
+ */
+pub struct FileRecReader<'a> {
+	reader: blockio::BlockRead<'a>,
+	remaining: usize,
+}
+
+impl<'a> FileRecReader<'a> {
+	/**
+	 * Reads the `header.num_recs` file recs from `input`, which must be
+	 * positioned right after `header`.
+	 */
+	pub fn new(input: &'a mut dyn Read, header: &Header) -> FileRecReader<'a> {
+		FileRecReader {
+			reader: blockio::BlockRead::new(input),
+			remaining: header.num_recs,
+		}
+	}
+
+	/**
+	 * The offset of the next file rec within the decoded (unblocked) stream.
+	 */
+	pub fn position(&self) -> u64 {
+		self.reader.position()
+	}
+
+	/**
+	 * The block size the file recs were written with, as far as can be
+	 * told from the ones read so far.
+	 */
+	pub fn block_size(&self) -> usize {
+		self.reader
+			.observed_block_size()
+			.unwrap_or(blockio::DEFAULT_BLOCK_SIZE)
+	}
+}
+
+impl<'a> Iterator for FileRecReader<'a> {
+	type Item = Result<FileRec, Box<dyn error::Error>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.remaining == 0 {
+			return None;
+		}
+
+		let result = FileRec::from_reader(&mut self.reader).map_err(|err| err.into());
+		self.remaining = if result.is_ok() {
+			self.remaining - 1
+		} else {
+			0
+		};
+
+		Some(result)
+	}
+
+	fn size_hint(&self) -> (usize, Option<usize>) {
+		(0, Some(self.remaining))
+	}
+}
+
+/**
+ * Parses an uninstall log, header and blocks, from any reader.
+ */
+pub fn read_from_reader<R: Read>(
+	reader: R,
+) -> Result<(Header, Vec<FileRec>), Box<dyn error::Error>> {
+	let (header, recs, _) = read_from_reader_with_offsets(reader)?;
+	Ok((header, recs.into_iter().map(|(_, rec)| rec).collect()))
+}
+
+/**
+ * Same as `read_file`, but also returns the block size the file was
+ * written with, so that it can be written back the same way.
+ */
+fn read_file_with_block_size(
+	path: &Path,
+) -> Result<(Header, Vec<FileRec>, usize), Box<dyn error::Error>> {
+	let (header, recs, block_size) = read_log(path)?;
+
+	Ok((
+		header,
+		recs.into_iter().map(|(_, rec)| rec).collect(),
+		block_size,
+	))
+}
+
+/**
+ * Reads all file recs, along with each one's offset within the decoded
+ * (unblocked) stream.
+ */
+fn read_file_with_offsets(path: &Path) -> Result<(Header, OffsetRecs), Box<dyn error::Error>> {
+	let (header, recs, _) = read_log(path)?;
+	Ok((header, recs))
+}
+
+fn read_from_reader_with_offsets<R: Read>(
+	mut input: R,
+) -> Result<(Header, OffsetRecs, usize), Box<dyn error::Error>> {
+	let header = Header::from_reader(&mut input)?;
+	let mut reader = FileRecReader::new(&mut input, &header);
+	let mut recs = Vec::with_capacity(header.num_recs);
+
+	let mut offset = reader.position();
+
+	while let Some(rec) = reader.next() {
+		recs.push((offset, rec?));
+		offset = reader.position();
+	}
+
+	let block_size = reader.block_size();
+
+	Ok((header, recs, block_size))
+}
+
+/**
+ * Writes an uninstall log over `path`, through a temp file.
+ */
+fn write_file(
+	path: &Path,
+	header: &Header,
+	recs: Vec<FileRec>,
+	block_size: usize,
+) -> Result<(), Box<dyn error::Error>> {
+	replace_through_temp_file(path, |temp_path| {
+		write_temp_file(temp_path, header, recs, block_size)
+	})
+}
+
+/**
+ * Has `write` write an uninstall log to a sibling temp file, which then
+ * replaces `path` in one go once it reads back, CRCs included. A failed or
+ * bad write therefore never touches `path`.
+ */
+fn replace_through_temp_file<F>(path: &Path, write: F) -> Result<(), Box<dyn error::Error>>
+where
+	F: FnOnce(&Path) -> Result<(), Box<dyn error::Error>>,
+{
+	let mut temp_path = path.to_path_buf().into_os_string();
+	temp_path.push(".tmp");
+	let temp_path = PathBuf::from(temp_path);
+
+	let result = write(&temp_path)
+		.and_then(|_| read_file(&temp_path))
+		.and_then(|_| replace_file(&temp_path, path).map_err(|err| err.into()));
+
+	if result.is_err() {
+		let _ = fs::remove_file(&temp_path);
+	}
+
+	result
+}
+
+fn write_temp_file(
+	path: &Path,
+	header: &Header,
+	recs: Vec<FileRec>,
+	block_size: usize,
+) -> Result<(), Box<dyn error::Error>> {
+	let output_file = fs::File::create(path)?;
+	let mut output = io::BufWriter::new(output_file);
+
+	write_to_writer_with_block_size(&mut output, header, recs, block_size)?;
+	output.flush()?;
+
+	// on disk before it replaces anything
+	output.get_ref().sync_all()?;
+
+	Ok(())
+}
+
+/**
+ * Moves `from` over `to`, replacing it.
+ */
+fn replace_file(from: &Path, to: &Path) -> Result<(), io::Error> {
+	use strings::to_u16s;
+	use windows_sys::Win32::Storage::FileSystem::{
+		MoveFileExW, MOVEFILE_REPLACE_EXISTING, MOVEFILE_WRITE_THROUGH,
+	};
+
+	let result = unsafe {
+		MoveFileExW(
+			to_u16s(from).as_ptr(),
+			to_u16s(to).as_ptr(),
+			MOVEFILE_REPLACE_EXISTING | MOVEFILE_WRITE_THROUGH,
+		)
+	};
+
+	if result == 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	Ok(())
+}
+
+/**
+ * Writes an uninstall log to any seekable writer. The blocks are streamed
+ * after a placeholder header, which is rewritten once their end offset is
+ * known.
+ */
+pub fn write_to_writer<W: Write + Seek>(
+	writer: W,
+	header: &Header,
+	recs: Vec<FileRec>,
+) -> Result<(), Box<dyn error::Error>> {
+	write_to_writer_with_block_size(writer, header, recs, blockio::DEFAULT_BLOCK_SIZE)
+}
+
+/**
+ * Same as `write_to_writer`, but with blocks of `block_size` bytes rather
+ * than the 4096 Inno Setup uses.
+ */
+pub fn write_to_writer_with_block_size<W: Write + Seek>(
+	mut writer: W,
+	header: &Header,
+	recs: Vec<FileRec>,
+	block_size: usize,
+) -> Result<(), Box<dyn error::Error>> {
+	let mut header = header.clone();
+	header.num_recs = recs.len();
+
+	let start = writer.stream_position()?;
+	header.to_writer(&mut writer)?;
+
+	{
+		let mut block_writer = blockio::BlockWrite::with_block_size(&mut writer, block_size);
+
+		for rec in recs {
+			rec.to_writer(&mut block_writer)?;
+		}
+
+		block_writer.flush()?;
+	}
+
+	let end = writer.stream_position()?;
+	header.set_end_offset(end - start)?;
+
+	writer.seek(io::SeekFrom::Start(start))?;
+	header.to_writer(&mut writer)?;
+	writer.seek(io::SeekFrom::Start(end))?;
+
+	Ok(())
+}
+
+/**
+ * Rewrites the uninstall log at `input` to `output`, recomputing the header
+ * and block CRCs. The CRCs of `input` aren't checked at all, so genuine
+ * corruption is carried over and masked. Returns the number of records.
+ */
+pub fn repair_crc(input: &Path, output: &Path) -> Result<usize, Box<dyn error::Error>> {
+	let input_file = fs::File::open(input)?;
+	let mut input = io::BufReader::new(input_file);
+
+	let header = Header::from_reader_lenient(&mut input)?;
+	let mut reader = blockio::BlockRead::lenient(&mut input);
+	let mut recs = Vec::with_capacity(header.num_recs);
+
+	for _ in 0..header.num_recs {
+		recs.push(FileRec::from_reader(&mut reader)?);
+	}
+
+	let block_size = reader
+		.observed_block_size()
+		.unwrap_or(blockio::DEFAULT_BLOCK_SIZE);
+
+	let num_recs = recs.len();
+	write_file(output, &header, recs, block_size)?;
+
+	Ok(num_recs)
+}
+
+/**
+ * Fixes the header CRC of the uninstall log at `path` in place, leaving
+ * every other byte as it is. Returns whether the CRC needed fixing.
+ */
+pub fn repair_header(path: &Path) -> Result<bool, Box<dyn error::Error>> {
+	let mut file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+	let mut buf = [0; model::HEADER_SIZE];
+	file.read_exact(&mut buf)?;
+
+	// make sure this is an uninstall log at all
+	Header::from_reader_lenient(&mut &buf[..])?;
+
+	let crc_offset = model::HEADER_SIZE - 4;
+	let crc = model::CRC32.checksum(&buf[..crc_offset]);
+
+	if buf[crc_offset..] == crc.to_le_bytes() {
+		return Ok(false);
+	}
+
+	file.seek(io::SeekFrom::Start(crc_offset as u64))?;
+	file.write_all(&crc.to_le_bytes())?;
+	file.flush()?;
+
+	Ok(true)
+}
+
+// how many threads open file handles at once
+const FILE_HANDLE_THREADS: usize = 8;
+
+/**
+ * Turns a boxed error, which can't cross threads, into an `io::Error`,
+ * which can, keeping its kind.
+ */
+fn into_io_error(err: Box<dyn error::Error>) -> io::Error {
+	match err.downcast::<io::Error>() {
+		Ok(err) => *err,
+		Err(err) => match err.downcast::<FileHandleError>() {
+			Ok(err) => (*err).into(),
+			Err(err) => io::Error::other(err.to_string()),
+		},
+	}
+}
+
+/**
+ * Gets an exclusive handle to `path`, retrying while other processes hold
+ * it. Only an `interactive` attempt asks whether to go on once it gives up.
+ */
+fn open_file_handle(
+	log: &slog::Logger,
+	path: &Path,
+	max_attempts: u32,
+	interactive: bool,
+) -> Result<FileHandle, io::Error> {
+	let msg = format!("Opening file handle: {:?}", path);
+
+	// only files held by other processes are worth waiting for
+	util::retry(
+		&msg,
+		|attempt| -> Result<FileHandle, Box<dyn error::Error>> {
+			info!(log, "Get file handle: {:?} (attempt {})", path, attempt);
+			FileHandle::new(path)
+		},
+		&util::RetryOptions {
+			max_attempts,
+			interactive,
+			retryable: FileHandleError::is_retryable_error,
+			..Default::default()
+		},
+	)
+	.map_err(into_io_error)
+}
+
+/**
+ * Gets exclusive handles to all `paths`, spreading the work over a few
+ * threads. The handles come back in the same order as `paths`. The
+ * threads never ask anything: the paths they fail on get another try
+ * here, which asks whether to go on if `interactive`, one at a time.
+ */
+fn open_file_handles(
+	log: &slog::Logger,
+	paths: &[PathBuf],
+	interactive: bool,
+) -> Result<Vec<FileHandle>, Box<dyn error::Error>> {
+	if paths.is_empty() {
+		return Ok(vec![]);
+	}
+
+	let chunk_size = paths.len().div_ceil(FILE_HANDLE_THREADS);
+
+	let results = thread::scope(|scope| {
+		let workers: Vec<_> = paths
+			.chunks(chunk_size)
+			.map(|chunk| {
+				scope.spawn(move || {
+					chunk
+						.iter()
+						.map(|path| open_file_handle(log, path, 16, false))
+						.collect::<Vec<Result<FileHandle, io::Error>>>()
+				})
+			})
+			.collect();
+
+		// join every worker, so that no handles are left behind on error
+		let mut results = Vec::with_capacity(paths.len());
+		let mut panicked = false;
+
+		for worker in workers {
+			match worker.join() {
+				Ok(chunk_results) => results.extend(chunk_results),
+				Err(_) => panicked = true,
+			}
+		}
+
+		if panicked {
+			return Err(io::Error::other("File handle thread panicked"));
+		}
+
+		Ok(results)
+	})?;
+
+	let mut file_handles = Vec::with_capacity(paths.len());
+
+	for (path, result) in paths.iter().zip(results) {
+		file_handles.push(match result {
+			Ok(file_handle) => file_handle,
+			Err(_) if interactive => open_file_handle(log, path, 1, true)?,
+			Err(err) => return Err(err.into()),
+		});
+	}
+
+	Ok(file_handles)
+}
+
+/**
+ * Reports `done` out of `total` steps of an update phase which spans from
+ * `from` to `to` percent.
+ */
+fn report_progress(window: &gui::ProgressWindow, from: u8, to: u8, done: usize, total: usize) {
+	if let Some(progress) = ((to - from) as usize * done).checked_div(total) {
+		window.set_progress(from + progress as u8);
+	}
+}
+
+/**
+ * Whether the top-level entry `entry_name`, of the installation root, is
+ * left alone by the update.
+ */
+fn is_left_in_place(entry_name: &str) -> bool {
+	// don't delete ourselves
+	entry_name == "tools"
+		// don't delete any of the unins* files
+		|| entry_name.starts_with("unins")
+		// don't delete the sparse package folder
+		|| entry_name == "appx"
+		// don't delete the bootstrap folder
+		|| entry_name == "bootstrap"
+}
+
+/**
+ * Whether `exe` lives somewhere the update deletes or moves files: under
+ * `root_path`, the update folder included, but not in any of the top-level
+ * entries which are left alone. Both paths should be canonical.
+ */
+pub fn is_in_update_tree(exe: &Path, root_path: &Path) -> bool {
+	match exe.strip_prefix(root_path) {
+		Ok(rest) => match rest.components().next() {
+			Some(std::path::Component::Normal(name)) => {
+				!name.to_str().is_some_and(is_left_in_place)
+			}
+			_ => false,
+		},
+		Err(_) => false,
+	}
+}
+
+/**
+ * Refuses to update if this very executable would be deleted or moved
+ * along with the installation, which would fail on its own locked image.
+ */
+fn check_not_in_update_tree(log: &slog::Logger, code_path: &Path) -> Result<(), io::Error> {
+	let exe = std::env::current_exe().and_then(fs::canonicalize)?;
+	let root_path = code_path
+		.parent()
+		.ok_or_else(|| io::Error::other("Could not get parent path of code"))
+		.and_then(fs::canonicalize)?;
+
+	info!(log, "Updater at {:?}, installation at {:?}", exe, root_path);
+
+	if is_in_update_tree(&exe, &root_path) {
+		return Err(io::Error::other(format!(
+				"The updater at {:?} is inside the installation it would update, it needs to run from the tools folder",
+				exe
+			),
+		));
+	}
+
+	Ok(())
+}
+
+/**
+ * Refuses an `update_folder_name` which isn't a single, normal path
+ * component: it gets joined onto the installation root, and entries named
+ * like it are spared from deletion.
+ */
+fn check_update_folder_name(update_folder_name: &str) -> Result<(), UpdateError> {
+	let is_component = !update_folder_name.is_empty()
+		&& update_folder_name != "."
+		&& update_folder_name != ".."
+		&& !update_folder_name.contains(&['/', '\\', ':'][..]);
+
+	if !is_component {
+		return Err(UpdateError::BadArguments(Box::new(ArgumentError(format!(
+			"Invalid update folder name: {:?}",
+			update_folder_name
+		)))));
+	}
+
+	Ok(())
+}
+
+/**
+ * Deletes a symlink or junction, leaving its target alone.
+ */
+fn remove_link(path: &Path) -> Result<(), io::Error> {
+	use std::os::windows::fs::MetadataExt;
+	use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_DIRECTORY;
+
+	// links to directories are removed the way empty directories are
+	if fs::symlink_metadata(path)?.file_attributes() & FILE_ATTRIBUTE_DIRECTORY != 0 {
+		fs::remove_dir(path)
+	} else {
+		fs::remove_file(path)
+	}
+}
+
+/**
+ * Best effort at marking every file left under `dir` for deletion, so that
+ * removing the directory can succeed once their handles are closed.
+ */
+fn mark_remaining_files(log: &slog::Logger, dir: &Path) {
+	let mut directories: LinkedList<PathBuf> = LinkedList::new();
+	directories.push_back(PathBuf::from(dir));
+
+	while let Some(dir) = directories.pop_front() {
+		let entries = match fs::read_dir(&dir) {
+			Ok(entries) => entries,
+			Err(_) => continue,
+		};
+
+		for entry in entries.filter_map(|entry| entry.ok()) {
+			let entry_path = entry.path();
+
+			match entry.file_type() {
+				Ok(file_type) if file_type.is_dir() => directories.push_back(entry_path),
+				Ok(file_type) if file_type.is_file() => {
+					let result =
+						FileHandle::new(&entry_path).and_then(|handle| handle.mark_for_deletion());
+
+					if let Err(err) = result {
+						warn!(log, "Failed to mark {:?} for deletion: {}", entry_path, err);
+					}
+				}
+				_ => (),
+			}
+		}
+	}
+}
+
+/**
+ * Fails once `deadline`, if any, has passed.
+ */
+fn check_deadline(deadline: Option<Instant>) -> Result<(), io::Error> {
+	match deadline {
+		Some(deadline) if Instant::now() >= deadline => Err(io::Error::new(
+			io::ErrorKind::TimedOut,
+			"Timed out deleting the existing version",
+		)),
+		_ => Ok(()),
+	}
+}
+
+/**
+ * How far `delete_existing_version` got.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DeleteProgress {
+	// files found, before any of them is touched
+	Collected(usize),
+	// files marked for deletion so far, out of all of them
+	Marked(usize, usize),
+	// files deleted so far, out of all of them
+	Deleted(usize, usize),
+	// top-level directories removed so far, out of all of them
+	RemovedDirectories(usize, usize),
+}
+
+/**
+ * Deletes everything under `root_path` but the update folder and the
+ * entries left in place, reporting along the way to `progress`. Until the
+ * files are all marked for deletion, `options.delete_timeout` still lets
+ * it give up without having deleted anything; after that it carries on,
+ * since the marked files go away once their handles are closed anyway.
+ */
+fn delete_existing_version(
+	log: &slog::Logger,
+	root_path: &Path,
+	update_folder_name: &str,
+	options: &UpdateOptions,
+	interactive: bool,
+	progress: &dyn Fn(DeleteProgress),
+) -> Result<(), Box<dyn error::Error>> {
+	let mut directories: LinkedList<PathBuf> = LinkedList::new();
+	let mut top_directories: LinkedList<PathBuf> = LinkedList::new();
+	let mut file_paths: Vec<PathBuf> = vec![];
+	let mut links: Vec<PathBuf> = vec![];
+
+	let deadline = options
+		.delete_timeout
+		.map(|timeout| Instant::now() + timeout);
+
+	let root = PathBuf::from(root_path);
+	directories.push_back(root);
+
+	while let Some(dir) = directories.pop_front() {
+		check_deadline(deadline)?;
+		info!(log, "Reading directory: {:?}", dir);
+
+		for entry in fs::read_dir(&dir)? {
+			let entry = entry?;
+			let entry_name = entry.file_name();
+			let entry_name = entry_name
+				.to_str()
+				.ok_or_else(|| io::Error::other("Could not get entry name"))?;
+
+			if dir == root_path {
+				// don't delete the update folder
+				if entry_name == update_folder_name {
+					continue;
+				}
+
+				if is_left_in_place(entry_name) {
+					continue;
+				}
+			}
+
+			let entry_file_type = entry.file_type()?;
+			let entry_path = entry.path();
+
+			if entry_file_type.is_dir() {
+				if dir == root_path {
+					top_directories.push_back(entry_path.to_owned());
+				}
+
+				directories.push_back(entry_path);
+			} else if entry_file_type.is_file() {
+				if options.dry_run {
+					info!(log, "WOULD delete file: {:?}", entry_path);
+				}
+
+				file_paths.push(entry_path);
+			} else if entry_file_type.is_symlink() {
+				// symlinks and junctions are deleted themselves, never followed,
+				// so that nothing outside the installation is touched
+				if options.dry_run {
+					info!(log, "WOULD delete link: {:?}", entry_path);
+				}
+
+				links.push(entry_path);
+			}
+		}
+	}
+
+	progress(DeleteProgress::Collected(file_paths.len()));
+
+	let file_handles = open_file_handles(log, &file_paths, interactive)?;
+
+	info!(
+		log,
+		"Collected all directories and {} file handles",
+		file_handles.len()
+	);
+	check_deadline(deadline)?;
+
+	if options.dry_run {
+		// closing handles which weren't marked for deletion deletes nothing
+		for file_handle in &file_handles {
+			file_handle.close()?;
+		}
+
+		for dir in top_directories {
+			info!(log, "WOULD delete directory recursively: {:?}", dir);
+		}
+
+		return Ok(());
+	}
+
+	for (i, file_handle) in file_handles.iter().enumerate() {
+		progress(DeleteProgress::Marked(i, file_handles.len()));
+
+		let result = check_deadline(deadline)
+			.map_err(|err| err.into())
+			.and_then(|_| {
+				util::retry(
+					"marking a file for deletion",
+					|_| -> Result<(), Box<dyn error::Error>> { file_handle.mark_for_deletion() },
+					&util::RetryOptions {
+						interactive,
+						..Default::default()
+					},
+				)
+			});
+
+		// files in use, like running executables, only fail here now: leave
+		// the others alone too, rather than delete part of the version
+		if let Err(err) = result {
+			for marked in &file_handles[..i] {
+				if let Err(err) = marked.unmark_for_deletion() {
+					warn!(log, "{}", err);
+				}
+			}
+
+			return Err(err);
+		}
+	}
+
+	info!(log, "All file handles marked for deletion");
+
+	if options.delete_grace > Duration::from_secs(0) {
+		info!(
+			log,
+			"Waiting {:?} before closing file handles", options.delete_grace
+		);
+		(options.sleep)(options.delete_grace);
+	}
+
+	for (i, file_handle) in file_handles.iter().enumerate() {
+		progress(DeleteProgress::Deleted(i, file_handles.len()));
+
+		util::retry(
+			"closing a file handle",
+			|_| -> Result<(), Box<dyn error::Error>> { file_handle.close() },
+			&util::RetryOptions {
+				interactive,
+				..Default::default()
+			},
+		)?;
+	}
+
+	progress(DeleteProgress::Deleted(
+		file_handles.len(),
+		file_handles.len(),
+	));
+
+	info!(log, "All files deleted");
+
+	for link in links {
+		let msg = format!("Deleting a link: {:?}", link);
+		util::retry(
+			&msg,
+			|attempt| -> Result<(), Box<dyn error::Error>> {
+				info!(log, "Delete link: {:?} (attempt {})", link, attempt);
+				remove_link(&link)?;
+				Ok(())
+			},
+			&util::RetryOptions {
+				interactive,
+				..Default::default()
+			},
+		)?;
+	}
+
+	let num_top_directories = top_directories.len();
+
+	for (i, dir) in top_directories.into_iter().enumerate() {
+		progress(DeleteProgress::RemovedDirectories(i, num_top_directories));
+
+		let msg = format!("Deleting a directory: {:?}", dir);
+		util::retry(
+			&msg,
+			|attempt| -> Result<(), Box<dyn error::Error>> {
+				if !dir.exists() {
+					return Ok(());
+				}
+
+				info!(
+					log,
+					"Delete directory recursively: {:?} (attempt {})", dir, attempt
+				);
+
+				if let Err(err) = fs::remove_dir_all(&dir) {
+					// some files may still be in use, or were recreated: mark
+					// whatever is left for deletion before trying again
+					warn!(log, "Failed to delete directory {:?}: {}", dir, err);
+					mark_remaining_files(log, &dir);
+					return Err(err.into());
+				}
+
+				Ok(())
+			},
+			&util::RetryOptions {
+				interactive,
+				..Default::default()
+			},
+		)?;
+	}
+
+	Ok(())
+}
+
+/**
+ * Shows deletion `progress` on `window`, as the first 80% of the update.
+ */
+fn show_delete_progress(window: &gui::ProgressWindow, progress: DeleteProgress) {
+	match progress {
+		DeleteProgress::Collected(count) => {
+			window.update_detail(&t("collected-files").replace("{count}", &count.to_string()));
+		}
+		DeleteProgress::Marked(done, total) => report_progress(window, 0, 40, done, total),
+		DeleteProgress::Deleted(done, total) => {
+			report_progress(window, 40, 70, done, total);
+			window.update_detail(
+				&t("deleted-files")
+					.replace("{done}", &done.to_string())
+					.replace("{total}", &total.to_string()),
+			);
+		}
+		DeleteProgress::RemovedDirectories(done, total) => {
+			report_progress(window, 70, 80, done, total)
+		}
+	}
+}
+
+fn move_update(
+	log: &slog::Logger,
+	uninstdat_path: &Path,
+	update_folder_name: &str,
+	options: &UpdateOptions,
+	window: &gui::ProgressWindow,
+) -> Result<(), UpdateError> {
+	info!(
+		log,
+		"move_update: {:?}, {}", uninstdat_path, update_folder_name
+	);
+
+	let root_path = uninstdat_path
+		.parent()
+		.ok_or_else(|| io::Error::other("Could not get parent path of uninstdat"))?;
+
+	let mut update_path = PathBuf::from(root_path);
+	update_path.push(update_folder_name);
+
+	let stat = fs::metadata(&update_path)?;
+
+	if !stat.is_dir() {
+		return Err(io::Error::other("Update folder is not a directory").into());
+	}
+
+	// safely delete all current files
+	delete_existing_version(
+		log,
+		root_path,
+		update_folder_name,
+		options,
+		!window.silent(),
+		&|progress| show_delete_progress(window, progress),
+	)
+	.map_err(|err| UpdateError::new(err, UpdateError::DeleteFailed))?;
+
+	// move update to current
+	let entries = fs::read_dir(&update_path)?.collect::<Result<Vec<_>, _>>()?;
+
+	for (i, entry) in entries.iter().enumerate() {
+		report_progress(window, 80, 100, i, entries.len());
+
+		let entry_name = entry.file_name();
+		let entry_name = entry_name
+			.to_str()
+			.ok_or_else(|| io::Error::other("Could not get entry name"))?;
+
+		let mut target = PathBuf::from(root_path);
+		target.push(entry_name);
+
+		if options.dry_run {
+			info!(log, "WOULD rename: {:?} to {:?}", entry.path(), target);
+			continue;
+		}
+
+		window.update_detail(entry_name);
+
+		let msg = format!("Renaming: {:?}", entry_name);
+		util::retry(
+			&msg,
+			|attempt| {
+				info!(log, "Rename: {:?} (attempt {})", entry_name, attempt);
+				fs::rename(entry.path(), &target)?;
+				Ok(())
+			},
+			&util::RetryOptions {
+				interactive: !window.silent(),
+				..Default::default()
+			},
+		)
+		.map_err(|err| {
+			UpdateError::new(err, |source| UpdateError::RenameFailed {
+				path: entry.path(),
+				source,
+			})
+		})?;
+	}
+
+	if options.dry_run {
+		info!(log, "WOULD delete: {:?}", update_path);
+		return Ok(());
+	}
+
+	info!(log, "Delete: {:?}", update_path);
+	fs::remove_dir_all(&update_path)?;
+
+	let entry_names = entries
+		.iter()
+		.map(|entry| entry.file_name())
+		.collect::<Vec<_>>();
+
+	verify_moved(root_path, &entry_names, &update_path)?;
+	window.set_progress(100);
+
+	Ok(())
+}
+
+/**
+ * Checks that every entry in `entry_names` landed in `root_path` and that
+ * the update folder is gone, so that a rename which silently went wrong
+ * fails the update rather than leaving a broken installation behind.
+ */
+fn verify_moved(
+	root_path: &Path,
+	entry_names: &[std::ffi::OsString],
+	update_path: &Path,
+) -> Result<(), io::Error> {
+	for entry_name in entry_names {
+		let target = root_path.join(entry_name);
+
+		// links count as in place whether or not their target exists
+		if fs::symlink_metadata(&target).is_err() {
+			return Err(io::Error::new(
+				io::ErrorKind::NotFound,
+				format!("{:?} is missing after the update", target),
+			));
+		}
+	}
+
+	if fs::symlink_metadata(update_path).is_ok() {
+		return Err(io::Error::other(format!(
+			"{:?} is still there after the update",
+			update_path
+		)));
+	}
+
+	Ok(())
+}
+
+/**
+ * Drops delete records whose type, flags and set of paths all match an
+ * earlier one, as repeated updates leave behind. Inno Setup runs records
+ * backwards on uninstall: directories are only removed once empty, so the
+ * copy of a directory record kept is the one which runs last, while for
+ * files it's the one which runs first, so no directory record sees a file
+ * it used to see deleted.
+ */
+fn dedup_recs(recs: Vec<FileRec>) -> Result<Vec<FileRec>, Box<dyn error::Error>> {
+	use std::collections::HashSet;
+
+	let mut keys = Vec::with_capacity(recs.len());
+
+	for rec in &recs {
+		keys.push(if rec.is_path_record() {
+			let mut paths = rec.get_paths()?;
+			paths.sort();
+			Some((rec.typ, rec.extra_data(), paths))
+		} else {
+			None
+		});
+	}
+
+	let mut keep = vec![true; recs.len()];
+	let mut seen = HashSet::new();
+
+	// directories: the first in the log runs last
+	for (i, key) in keys.iter().enumerate() {
+		if let Some(ref key) = *key {
+			if key.0 == model::UninstallRecTyp::DeleteDirOrFiles && !seen.insert(key) {
+				keep[i] = false;
+			}
+		}
+	}
+
+	// files: the last in the log runs first
+	for (i, key) in keys.iter().enumerate().rev() {
+		if let Some(ref key) = *key {
+			if key.0 == model::UninstallRecTyp::DeleteFile && !seen.insert(key) {
+				keep[i] = false;
+			}
+		}
+	}
+
+	Ok(recs
+		.into_iter()
+		.zip(keep)
+		.filter(|&(_, keep)| keep)
+		.map(|(rec, _)| rec)
+		.collect())
+}
+
+fn patch_uninstdat(
+	log: &slog::Logger,
+	uninstdat_path: &PathBuf,
+	update_folder_name: &str,
+	expected_app_id: Option<&str>,
+	dry_run: bool,
+) -> Result<(), Box<dyn error::Error>> {
+	let (header, recs, block_size) = read_file_with_block_size(uninstdat_path)?;
+
+	info!(log, "header: {:?}", header);
+	info!(log, "num_recs: {:?}", recs.len());
+
+	if let Some(expected_app_id) = expected_app_id {
+		// app ids are usually GUIDs, whose case doesn't matter
+		if !header.app_id().eq_ignore_ascii_case(expected_app_id) {
+			return Err(io::Error::other(format!(
+				"Uninstall file {:?} belongs to app {}, not {}",
+				uninstdat_path,
+				header.app_id(),
+				expected_app_id
+			))
+			.into());
+		}
+	}
+
+	let root_path = uninstdat_path
+		.parent()
+		.ok_or_else(|| io::Error::other("Could not get parent path of uninstdat"))?;
+
+	let mut update_path = PathBuf::from(root_path);
+	update_path.push(update_folder_name);
+
+	let recs: Result<Vec<FileRec>, _> = recs
+		.iter()
+		.map(|rec| {
+			if rec.is_path_record() {
+				rec.rebase(&update_path)
+			} else {
+				Ok(rec.clone())
+			}
+		})
+		.collect();
+
+	let recs = recs?;
+	let num_recs = recs.len();
+	let recs = dedup_recs(recs)?;
+
+	if recs.len() < num_recs {
+		info!(log, "Dropping {} duplicate records", num_recs - recs.len());
+	}
+
+	if dry_run {
+		info!(log, "WOULD update uninstall file {:?}", uninstdat_path);
+		return Ok(());
+	}
+
+	info!(log, "Updating uninstall file {:?}", uninstdat_path);
+	write_file(uninstdat_path, &header, recs, block_size)
+}
+
+fn do_update(
+	log: &slog::Logger,
+	code_path: &PathBuf,
+	update_folder_name: &str,
+	options: &UpdateOptions,
+	window: &gui::ProgressWindow,
+) -> Result<(), UpdateError> {
+	info!(log, "do_update: {:?}, {}", code_path, update_folder_name);
+
+	let root_path = code_path
+		.parent()
+		.ok_or_else(|| io::Error::other("Could not get parent path of uninstdat"))?;
+
+	let mut uninstdat_path = PathBuf::from(root_path);
+	uninstdat_path.push(
+		options
+			.uninst_name
+			.as_ref()
+			.map_or("unins000.dat", |name| name.as_str()),
+	);
+
+	move_update(log, &uninstdat_path, update_folder_name, options, window)?;
+
+	if !options.dry_run && !code_path.exists() {
+		return Err(io::Error::new(
+			io::ErrorKind::NotFound,
+			format!("{:?} is missing after the update", code_path),
+		)
+		.into());
+	}
+
+	// if, for any reason, the uninstdat file is corrupt, let's continue silently
+	// https://github.com/Microsoft/vscode/issues/45607
+	patch_uninstdat(
+		log,
+		&uninstdat_path,
+		update_folder_name,
+		options.expected_app_id.as_deref(),
+		options.dry_run,
+	)
+	.unwrap_or_else(|err| {
+		warn!(log, "Failed to patch uninst.dat file");
+		warn!(log, "{}", err);
+	});
+
+	Ok(())
+}
+
+fn update(
+	log: &slog::Logger,
+	code_path: &PathBuf,
+	update_folder_name: &str,
+	silent: bool,
+	label: String,
+	options: &UpdateOptions,
+) -> Result<(), UpdateError> {
+	check_update_folder_name(update_folder_name)?;
+	check_not_in_update_tree(log, code_path)?;
+
+	process::wait_or_kill(log, code_path, &options.wait, options.dry_run)
+		.map_err(|err| UpdateError::new(err, UpdateError::ProcessKillFailed))?;
+
+	info!(log, "Inno Updater v{}", VERSION);
+	info!(
+		log,
+		"Starting update, silent = {}, dry run = {}", silent, options.dry_run
+	);
+
+	let (tx, rx) = mpsc::channel();
+
+	let ui_thread = thread::spawn(move || {
+		gui::run_progress_window(silent, tx, label);
+	});
+
+	let window = rx
+		.recv()
+		.map_err(|_| io::Error::other("Could not receive GUI window handle"))?;
+
+	if let Some(err) = window.creation_error() {
+		warn!(
+			log,
+			"Could not create the progress dialog, updating without it: {}", err
+		);
+	}
+
+	if window.off_center() {
+		warn!(
+			log,
+			"Desktop window has an empty rect, progress dialog was not centered"
+		);
+	}
+
+	do_update(log, code_path, update_folder_name, options, &window)?;
+
+	let lifetime = window.lifetime();
+	if !silent && lifetime < Duration::from_millis(100) {
+		warn!(log, "Progress dialog was only up for {:?}", lifetime);
+	}
+
+	// wait for the dialog to actually go away before carrying on
+	window.exit();
+	ui_thread
+		.join()
+		.map_err(|_| io::Error::other("GUI thread panicked"))?;
+
+	Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct ArgumentError(pub String);
+
+impl fmt::Display for ArgumentError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Bad arguments: {}", self.0)
+	}
+}
+
+impl error::Error for ArgumentError {
+	fn description(&self) -> &str {
+		"ArgumentError"
+	}
+
+	fn cause(&self) -> Option<&dyn error::Error> {
+		None
+	}
+}
+
+#[derive(Debug)]
+pub enum UpdateError {
+	BadArguments(Box<dyn error::Error>),
+	ProcessKillFailed(Box<dyn error::Error>),
+	DeleteFailed(Box<dyn error::Error>),
+	RenameFailed {
+		path: PathBuf,
+		source: Box<dyn error::Error>,
+	},
+	PermissionDenied(Box<dyn error::Error>),
+	Other(Box<dyn error::Error>),
+}
+
+impl UpdateError {
+	/**
+	 * Wraps `err` with `variant`, unless it is a permission error, which
+	 * gets its own variant whichever step it came from.
+	 */
+	fn new<F: FnOnce(Box<dyn error::Error>) -> UpdateError>(
+		err: Box<dyn error::Error>,
+		variant: F,
+	) -> UpdateError {
+		let permission_denied = err
+			.downcast_ref::<io::Error>()
+			.is_some_and(|err| err.kind() == io::ErrorKind::PermissionDenied);
+
+		if permission_denied {
+			UpdateError::PermissionDenied(err)
+		} else {
+			variant(err)
+		}
+	}
+
+	/**
+	 * A distinct process exit code for each kind of failure.
+	 */
+	pub fn exit_code(&self) -> i32 {
+		match *self {
+			UpdateError::BadArguments(_) => 2,
+			UpdateError::Other(_) => 3,
+			UpdateError::PermissionDenied(_) => 5,
+			UpdateError::ProcessKillFailed(_) => 6,
+			UpdateError::DeleteFailed(_) => 7,
+			UpdateError::RenameFailed { .. } => 8,
+		}
+	}
+}
+
+/**
+ * Whether `err` comes from an uninstall log which doesn't parse: a bad
+ * header, block or record, or a file cut short. Logs which can't be read
+ * at all fail with other errors.
+ */
+pub fn is_parse_error(err: &(dyn error::Error + 'static)) -> bool {
+	err.is::<model::HeaderParseError<'static>>()
+		|| err.is::<model::FileRecParseError<'static>>()
+		|| err
+			.downcast_ref::<io::Error>()
+			.is_some_and(|err| err.kind() == io::ErrorKind::InvalidData)
+}
+
+impl fmt::Display for UpdateError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			UpdateError::BadArguments(ref err) => write!(f, "{}", err),
+			UpdateError::ProcessKillFailed(ref err) => {
+				write!(f, "Failed to stop running processes: {}", err)
+			}
+			UpdateError::DeleteFailed(ref err) => {
+				write!(f, "Failed to delete the existing version: {}", err)
+			}
+			UpdateError::RenameFailed {
+				ref path,
+				ref source,
+			} => write!(f, "Failed to rename {:?}: {}", path, source),
+			UpdateError::PermissionDenied(ref err) => write!(f, "Permission denied: {}", err),
+			UpdateError::Other(ref err) => write!(f, "{}", err),
+		}
+	}
+}
+
+impl error::Error for UpdateError {
+	fn description(&self) -> &str {
+		"UpdateError"
+	}
+
+	fn cause(&self) -> Option<&dyn error::Error> {
+		match *self {
+			UpdateError::BadArguments(ref err)
+			| UpdateError::ProcessKillFailed(ref err)
+			| UpdateError::DeleteFailed(ref err)
+			| UpdateError::RenameFailed {
+				source: ref err, ..
+			}
+			| UpdateError::PermissionDenied(ref err)
+			| UpdateError::Other(ref err) => Some(err.as_ref()),
+		}
+	}
+}
+
+impl From<Box<dyn error::Error>> for UpdateError {
+	fn from(err: Box<dyn error::Error>) -> UpdateError {
+		UpdateError::new(err, UpdateError::Other)
+	}
+}
+
+impl From<io::Error> for UpdateError {
+	fn from(err: io::Error) -> UpdateError {
+		UpdateError::from(Box::<dyn error::Error>::from(err))
+	}
+}
+
+pub struct UpdateOptions {
+	pub wait: process::WaitOptions,
+	// log every destructive action instead of performing it
+	pub dry_run: bool,
+	// pause between marking files for deletion and closing their handles
+	pub delete_grace: Duration,
+	// how to pause for `delete_grace`
+	pub sleep: fn(Duration),
+	// give up deleting the existing version if its files aren't all marked
+	// for deletion after this long, leaving it intact
+	pub delete_timeout: Option<Duration>,
+	// file name of the uninstall log, if not unins000.dat
+	pub uninst_name: Option<String>,
+	// app id the uninstall log must belong to before it's patched
+	pub expected_app_id: Option<String>,
+}
+
+impl Default for UpdateOptions {
+	fn default() -> UpdateOptions {
+		UpdateOptions {
+			wait: process::WaitOptions::default(),
+			dry_run: false,
+			delete_grace: Duration::from_secs(0),
+			sleep: thread::sleep,
+			delete_timeout: None,
+			uninst_name: None,
+			expected_app_id: None,
+		}
+	}
+}
+
+/**
+ * Applies the update found in `update_folder_name`, next to `code_path`,
+ * after waiting for (or killing) any running instances of it.
+ */
+pub fn run_update(
+	log: &slog::Logger,
+	code_path: &Path,
+	update_folder_name: &str,
+	silent: bool,
+	label: String,
+) -> Result<(), UpdateError> {
+	run_update_with_options(
+		log,
+		code_path,
+		update_folder_name,
+		silent,
+		label,
+		&UpdateOptions::default(),
+	)
+}
+
+pub fn run_update_with_options(
+	log: &slog::Logger,
+	code_path: &Path,
+	update_folder_name: &str,
+	silent: bool,
+	label: String,
+	options: &UpdateOptions,
+) -> Result<(), UpdateError> {
+	update(
+		log,
+		&code_path.to_path_buf(),
+		update_folder_name,
+		silent,
+		label,
+		options,
+	)
+}
+
+#[derive(Default)]
+pub struct ParseOptions {
+	pub validate_utf16: bool,
+	pub offsets: bool,
+	pub expect_arch: Option<String>,
+	pub grep: Option<String>,
+	pub check_size: bool,
+	pub check_delete: Option<PathBuf>,
+	pub flags: bool,
+	pub json: bool,
+	pub decode: bool,
+	pub prometheus: bool,
+	pub check_encoding: bool,
+	pub strings: bool,
+	pub relative_to: Option<String>,
+	pub manifest: Option<PathBuf>,
+}
+
+/**
+ * The paths of the records of the log at `path`, with the root the log
+ * sits in, which is the one it was installed to, replaced by `root`. Each
+ * path comes with the index of its record.
+ */
+fn rebased_paths(
+	path: &Path,
+	recs: &[(u64, FileRec)],
+	root: &Path,
+) -> Result<Vec<(usize, String)>, Box<dyn error::Error>> {
+	let install_root = path
+		.parent()
+		.and_then(|p| p.to_str())
+		.ok_or(model::RebaseError)?;
+
+	let root = root
+		.to_str()
+		.ok_or(model::RebaseError)?
+		.trim_end_matches('\\');
+	let mut result = vec![];
+
+	for (i, (_, rec)) in recs.iter().enumerate() {
+		for rec_path in rec.get_paths()? {
+			let rec_path = match strings::strip_path_prefix(&rec_path, install_root) {
+				Some(rest) => format!("{}{}", root, rest),
+				None => rec_path,
+			};
+
+			result.push((i, rec_path));
+		}
+	}
+
+	Ok(result)
+}
+
+/**
+ * Prints the CRC32 of every file the log at `path` refers to, as found
+ * under `root` now, so drift from the recorded install can be spotted.
+ * Directories are skipped and missing files are reported as such.
+ */
+fn print_manifest(
+	path: &Path,
+	recs: &[(u64, FileRec)],
+	root: &Path,
+	json: bool,
+	output: &mut dyn Write,
+) -> Result<(), Box<dyn error::Error>> {
+	let mut entries: Vec<String> = vec![];
+
+	for (_, rec_path) in rebased_paths(path, recs, root)? {
+		let metadata = match fs::metadata(&rec_path) {
+			Ok(metadata) => Some(metadata),
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+			Err(err) => return Err(err.into()),
+		};
+
+		let crc = match metadata {
+			Some(ref metadata) if metadata.is_dir() => continue,
+			Some(_) => Some(util::hash_file(Path::new(&rec_path), |_, _| {}, None)?),
+			None => None,
+		};
+
+		entries.push(match (json, crc) {
+			(true, Some(crc)) => format!(
+				"{{\"path\": {}, \"crc\": {}}}",
+				strings::to_json_string(&rec_path),
+				crc
+			),
+			(true, None) => format!(
+				"{{\"path\": {}, \"missing\": true}}",
+				strings::to_json_string(&rec_path)
+			),
+			(false, Some(crc)) => format!("{:08x} {}", crc, rec_path),
+			(false, None) => format!("missing  {}", rec_path),
+		});
+	}
+
+	if json {
+		writeln!(output, "[{}]", entries.join(", "))?;
+	} else {
+		for entry in entries {
+			writeln!(output, "{}", entry)?;
+		}
+	}
+
+	Ok(())
+}
+
+/**
+ * Strips `root` off `path`, ignoring case, as Windows does. Paths outside
+ * `root` are kept absolute, with a marker so they stand out in a diff.
+ */
+fn relative_path(path: &str, root: &str) -> String {
+	match strings::strip_path_prefix(path, root) {
+		Some("") => String::from("."),
+		Some(rest) => String::from(&rest[1..]),
+		None => format!("(outside) {}", path),
+	}
+}
+
+pub fn parse(path: &Path, options: &ParseOptions) -> Result<(), Box<dyn error::Error>> {
+	let stdout = io::stdout();
+	parse_to_writer(path, options, &mut stdout.lock())
+}
+
+fn parse_to_writer(
+	path: &Path,
+	options: &ParseOptions,
+	output: &mut dyn Write,
+) -> Result<(), Box<dyn error::Error>> {
+	let (header, recs) = read_file_with_offsets(path)?;
+
+	if let Some(ref root) = options.manifest {
+		return print_manifest(path, &recs, root, options.json, output);
+	}
+
+	if options.json {
+		let recs: Result<Vec<String>, _> = recs.iter().map(|(_, rec)| rec.to_json()).collect();
+
+		writeln!(
+			output,
+			"{{\"header\": {}, \"records\": [{}]}}",
+			header.to_json(),
+			recs?.join(", ")
+		)?;
+
+		return Ok(());
+	}
+
+	if options.prometheus {
+		use std::collections::BTreeMap;
+		let mut map: BTreeMap<&str, u32> = BTreeMap::new();
+
+		for (_, rec) in &recs {
+			*map.entry(rec.typ.name()).or_insert(0) += 1;
+		}
+
+		writeln!(
+			output,
+			"# HELP inno_uninstall_records Number of uninstall log records by type."
+		)?;
+		writeln!(output, "# TYPE inno_uninstall_records gauge")?;
+
+		for (name, count) in &map {
+			writeln!(
+				output,
+				"inno_uninstall_records{{type=\"{}\"}} {}",
+				name, count
+			)?;
+		}
+
+		return Ok(());
+	}
+
+	writeln!(output, "{:?}", header)?;
+
+	let arch = if header.is_64bit() { "x64" } else { "x86" };
+	writeln!(output, "Architecture: {}", arch)?;
+
+	if let Some(ref expect_arch) = options.expect_arch {
+		if expect_arch != arch {
+			return Err(io::Error::other(format!(
+				"Expected a {} uninstall log, found {}",
+				expect_arch, arch
+			))
+			.into());
+		}
+	}
+
+	if options.offsets {
+		for (i, (offset, rec)) in recs.iter().enumerate() {
+			writeln!(
+				output,
+				"Record {} at offset {} ({} bytes): {:?}",
+				i,
+				offset,
+				rec.encoded_len(),
+				rec
+			)?;
+		}
+	}
+
+	if options.decode {
+		for (i, (_, rec)) in recs.iter().enumerate() {
+			writeln!(
+				output,
+				"Record {} {}: {}",
+				i,
+				rec.typ.name(),
+				rec.describe()
+			)?;
+		}
+	}
+
+	if options.strings {
+		use std::collections::BTreeMap;
+		let mut map: BTreeMap<&str, Vec<(usize, Vec<String>)>> = BTreeMap::new();
+
+		for (i, (_, rec)) in recs.iter().enumerate() {
+			let strings = rec.get_strings()?;
+
+			if !strings.is_empty() {
+				map.entry(rec.typ.name()).or_default().push((i, strings));
+			}
+		}
+
+		for (name, entries) in &map {
+			writeln!(output, "{}:", name)?;
+
+			for &(i, ref strings) in entries {
+				writeln!(output, "\t{}: {}", i, strings.join(", "))?;
+			}
+		}
+	}
+
+	let display_path = |path: String| match options.relative_to {
+		Some(ref root) => relative_path(&path, root),
+		None => path,
+	};
+
+	if options.flags {
+		for (i, (_, rec)) in recs.iter().enumerate() {
+			for path in rec.get_paths()? {
+				writeln!(
+					output,
+					"{}: [{}] {}",
+					i,
+					rec.describe_flags(),
+					display_path(path)
+				)?;
+			}
+		}
+	}
+
+	if let Some(ref grep) = options.grep {
+		let grep = grep.to_lowercase();
+
+		for (i, (_, rec)) in recs.iter().enumerate() {
+			let paths = rec.get_paths()?;
+
+			if paths.iter().any(|p| p.to_lowercase().contains(&grep)) {
+				for path in paths {
+					writeln!(output, "{}: {}", i, display_path(path))?;
+				}
+			}
+		}
+	}
+
+	if let Some(ref root) = options.check_delete {
+		for (i, rec_path) in rebased_paths(path, &recs, root)? {
+			if !FileHandle::can_delete(Path::new(&rec_path))? {
+				writeln!(output, "Record {}: cannot delete {}", i, rec_path)?;
+			}
+		}
+	}
+
+	if options.check_size {
+		let len = fs::metadata(path)?.len();
+		let end_offset = header.end_offset as u64;
+
+		// shorter files don't get this far
+		if len > end_offset {
+			writeln!(
+				output,
+				"Warning: {} trailing bytes after end offset {}",
+				len - end_offset,
+				end_offset
+			)?;
+		}
+	}
+
+	if options.check_encoding {
+		for (i, (_, rec)) in recs.iter().enumerate() {
+			if rec.looks_misencoded() {
+				writeln!(output, "Record {} may be misencoded: {}", i, rec.describe())?;
+			}
+		}
+	}
+
+	if options.validate_utf16 {
+		for (i, (_, rec)) in recs.iter().enumerate() {
+			if let Err(err) = rec.validate_strings() {
+				writeln!(output, "Record {} is malformed: {}", i, err)?;
+				break;
+			}
+		}
+	}
+
+	use std::collections::HashMap;
+	let mut map: HashMap<u16, u32> = HashMap::new();
+
+	for (_, rec) in recs {
+		let count = map.entry(rec.typ.value()).or_insert(0);
+		*count += 1;
+	}
+
+	for (k, c) in &map {
+		writeln!(output, "Records 0x{:x} {}", k, c)?;
+	}
+
+	Ok(())
+}
+
+pub struct VerifyReport {
+	pub num_recs: usize,
+	// findings which don't stop the log from being used
+	pub warnings: Vec<String>,
+}
+
+/**
+ * Checks that an uninstall log is sound, without modifying it. Fatal
+ * corruption, like a failed header or block CRC check, a record which
+ * doesn't parse or a file shorter than its end offset, is an error; data
+ * past the end offset, record types we don't know and malformed UTF-16
+ * paths are reported as warnings.
+ */
+pub fn verify(path: &Path) -> Result<VerifyReport, Box<dyn error::Error>> {
+	let input_file = fs::File::open(path)?;
+	let len = input_file.metadata()?.len();
+	let mut input = io::BufReader::new(input_file);
+	let header = Header::from_reader(&mut input)?;
+	let mut warnings: Vec<String> = vec![];
+	let mut num_recs = 0;
+
+	check_not_truncated(len, &header)?;
+
+	let end_offset = header.end_offset as u64;
+
+	if len > end_offset {
+		warnings.push(format!(
+			"{} trailing bytes after end offset {}",
+			len - end_offset,
+			end_offset
+		));
+	}
+
+	// one record at a time, logs can be huge
+	for (i, rec) in FileRecReader::new(&mut input, &header).enumerate() {
+		let rec = rec?;
+		num_recs += 1;
+
+		if let model::UninstallRecTyp::Unknown(typ) = rec.typ {
+			warnings.push(format!("Record {} has unknown type 0x{:x}", i, typ));
+		}
+
+		// paths which don't even split up can't be rewritten by an update,
+		// while a lone surrogate in one is only odd
+		if let Err(err) = rec.validate_strings() {
+			if rec.is_path_record() && !rec.is_string_table_intact() {
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("Record {}: {}", i, err),
+				)
+				.into());
+			}
+
+			warnings.push(format!("Record {} is malformed: {}", i, err));
+		}
+	}
+
+	Ok(VerifyReport { num_recs, warnings })
+}
+
+/**
+ * Prints a stable, deterministic text rendering of the whole uninstall log,
+ * suitable for golden files.
+ */
+pub fn dump(path: &Path) -> Result<(), Box<dyn error::Error>> {
+	let stdout = io::stdout();
+	dump_to_writer(path, &mut stdout.lock())
+}
+
+fn dump_to_writer(path: &Path, output: &mut dyn Write) -> Result<(), Box<dyn error::Error>> {
+	let input_file = fs::File::open(path)?;
+	let len = input_file.metadata()?.len();
+	let mut input = io::BufReader::new(input_file);
+	let header = Header::from_reader(&mut input)?;
+
+	check_not_truncated(len, &header)?;
+
+	writeln!(output, "{:?}", header)?;
+
+	for (i, rec) in FileRecReader::new(&mut input, &header).enumerate() {
+		let rec = rec?;
+		writeln!(
+			output,
+			"Record {}: {} 0x{:x}, extra data 0x{:x}, {} bytes",
+			i,
+			rec.typ.name(),
+			rec.typ.value(),
+			rec.extra_data(),
+			rec.encoded_len()
+		)?;
+		writeln!(output, "\t{}", rec.describe())?;
+	}
+
+	Ok(())
+}
+
+/**
+ * Compares two uninstall logs, printing how many records and delete paths
+ * were added and removed going from `old` to `new`; `verbose` also lists
+ * the paths themselves.
+ */
+pub fn diff(old: &Path, new: &Path, verbose: bool) -> Result<(), Box<dyn error::Error>> {
+	use std::collections::{BTreeSet, HashMap};
+
+	let (_, old_recs) = read_file(old)?;
+	let (_, new_recs) = read_file(new)?;
+
+	// records may repeat, so count them rather than collecting them in a set
+	let mut counts: HashMap<&FileRec, i64> = HashMap::new();
+
+	for rec in &old_recs {
+		*counts.entry(rec).or_insert(0) -= 1;
+	}
+
+	for rec in &new_recs {
+		*counts.entry(rec).or_insert(0) += 1;
+	}
+
+	let recs_added: i64 = counts.values().filter(|&&c| c > 0).sum();
+	let recs_removed: i64 = -counts.values().filter(|&&c| c < 0).sum::<i64>();
+
+	let paths = |recs: &[FileRec]| -> Result<BTreeSet<String>, Box<dyn error::Error>> {
+		let mut paths = BTreeSet::new();
+
+		for rec in recs {
+			paths.extend(rec.get_paths()?);
+		}
+
+		Ok(paths)
+	};
+
+	let old_paths = paths(&old_recs)?;
+	let new_paths = paths(&new_recs)?;
+	let paths_added: Vec<&String> = new_paths.difference(&old_paths).collect();
+	let paths_removed: Vec<&String> = old_paths.difference(&new_paths).collect();
+
+	println!(
+		"{} records, was {}: {} added, {} removed",
+		new_recs.len(),
+		old_recs.len(),
+		recs_added,
+		recs_removed
+	);
+	println!(
+		"{} paths added, {} removed",
+		paths_added.len(),
+		paths_removed.len()
+	);
+
+	if verbose {
+		for path in paths_added {
+			println!("+ {}", path);
+		}
+
+		for path in paths_removed {
+			println!("- {}", path);
+		}
+	}
+
+	Ok(())
+}
+
+/**
+ * Reports the files in the installation next to `code_path` which other
+ * processes have open, failing if there are any.
+ */
+pub fn check_locks(code_path: &Path) -> Result<(), Box<dyn error::Error>> {
+	let root_path = code_path
+		.parent()
+		.ok_or_else(|| io::Error::other("Could not get parent path of code path"))?;
+
+	let locked_files = locks::find_locked_files(root_path)?;
+
+	for locked_file in &locked_files {
+		for locker in &locked_file.lockers {
+			println!(
+				"{}: {} (pid {})",
+				locked_file.path.display(),
+				locker.name,
+				locker.id
+			);
+		}
+	}
+
+	if !locked_files.is_empty() {
+		return Err(io::Error::other(format!("{} files are in use", locked_files.len())).into());
+	}
+
+	Ok(())
+}
+
+/**
+ * Prints the running processes, as the updater enumerates them, one per
+ * line: id, name and path, separated by tabs. The path is `-` when the
+ * process couldn't be queried for it. `filter`, if any, keeps only the
+ * processes whose name contains it, ignoring case.
+ */
+pub fn list_processes(filter: Option<&str>) -> Result<(), Box<dyn error::Error>> {
+	let filter = filter.map(|filter| filter.to_lowercase());
+
+	for process in process::get_running_processes()? {
+		if let Some(ref filter) = filter {
+			if !process.name.to_lowercase().contains(filter.as_str()) {
+				continue;
+			}
+		}
+
+		let path = process
+			.path
+			.as_ref()
+			.map_or(String::from("-"), |path| path.display().to_string());
+
+		println!("{}\t{}\t{}", process.id, process.name, path);
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use byteorder::{ByteOrder, LittleEndian};
+	use std::cell::RefCell;
+
+	fn logger() -> slog::Logger {
+		slog::Logger::root(slog::Discard, o!())
+	}
+
+	/**
+	 * The header of an empty 32-bit log.
+	 */
+	fn header() -> Header {
+		header_with_id("Inno Setup Uninstall Log (b)")
+	}
+
+	fn header_with_id(id: &str) -> Header {
+		let mut bytes = vec![0; model::HEADER_SIZE];
+		bytes[..id.len()].copy_from_slice(id.as_bytes());
+		LittleEndian::write_i32(&mut bytes[320..324], 1048);
+		LittleEndian::write_u32(&mut bytes[328..332], model::HEADER_SIZE as u32);
+
+		let crc = model::CRC32.checksum(&bytes[..model::HEADER_SIZE - 4]);
+		LittleEndian::write_u32(&mut bytes[model::HEADER_SIZE - 4..], crc);
+
+		Header::from_reader(&mut &bytes[..]).unwrap()
+	}
+
+	/**
+	 * A record of type `typ` holding `units` as its one UTF-16 string.
+	 */
+	fn rec(typ: u16, units: &[u16]) -> FileRec {
+		let mut data = vec![0xfe; 5];
+		LittleEndian::write_i32(&mut data[1..5], -(units.len() as i32 * 2));
+
+		for &unit in units {
+			data.extend_from_slice(&unit.to_le_bytes());
+		}
+
+		data.push(0xff);
+
+		let mut bytes = vec![0; 10];
+		LittleEndian::write_u16(&mut bytes[0..2], typ);
+		LittleEndian::write_u32(&mut bytes[6..10], data.len() as u32);
+		bytes.extend_from_slice(&data);
+
+		FileRec::from_reader(&mut &bytes[..]).unwrap()
+	}
+
+	/**
+	 * A record of type `typ` holding `paths`, each as a UTF-16 string.
+	 */
+	fn paths_rec(typ: u16, paths: &[&str]) -> FileRec {
+		let mut data = vec![];
+
+		for path in paths {
+			let units: Vec<u16> = path.encode_utf16().collect();
+			let mut len = [0; 4];
+			LittleEndian::write_i32(&mut len, -(units.len() as i32 * 2));
+
+			data.push(0xfe);
+			data.extend_from_slice(&len);
+
+			for unit in units {
+				data.extend_from_slice(&unit.to_le_bytes());
+			}
+		}
+
+		data.push(0xff);
+
+		let mut bytes = vec![0; 10];
+		LittleEndian::write_u16(&mut bytes[0..2], typ);
+		LittleEndian::write_u32(&mut bytes[6..10], data.len() as u32);
+		bytes.extend_from_slice(&data);
+
+		FileRec::from_reader(&mut &bytes[..]).unwrap()
+	}
+
+	fn path_rec(path: &str) -> FileRec {
+		paths_rec(0x82, &[path])
+	}
+
+	fn log_bytes(recs: Vec<FileRec>) -> Vec<u8> {
+		let mut bytes = io::Cursor::new(vec![]);
+		write_to_writer(&mut bytes, &header(), recs).unwrap();
+		bytes.into_inner()
+	}
+
+	/**
+	 * An empty directory of its own for the test `name`.
+	 */
+	fn temp_dir(name: &str) -> PathBuf {
+		let dir =
+			std::env::temp_dir().join(format!("inno-updater-{}-{}", name, std::process::id()));
+
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	/**
+	 * An installation with two files to delete, an update folder named `_`
+	 * and an uninstall log to leave alone.
+	 */
+	fn fake_installation(name: &str) -> PathBuf {
+		let root = temp_dir(name);
+		fs::create_dir_all(root.join("resources").join("app")).unwrap();
+		fs::create_dir_all(root.join("_")).unwrap();
+		fs::write(root.join("Code.exe"), b"old").unwrap();
+		fs::write(
+			root.join("resources").join("app").join("package.json"),
+			b"{}",
+		)
+		.unwrap();
+		fs::write(root.join("_").join("Code.exe"), b"new").unwrap();
+		fs::write(root.join("unins000.dat"), b"log").unwrap();
+		root
+	}
+
+	#[test]
+	fn delete_existing_version_reports_progress() {
+		let root = fake_installation("delete-progress");
+		let reported = RefCell::new(vec![]);
+
+		delete_existing_version(
+			&logger(),
+			&root,
+			"_",
+			&UpdateOptions::default(),
+			false,
+			&|progress| reported.borrow_mut().push(progress),
+		)
+		.unwrap();
+
+		let reported = reported.into_inner();
+		assert_eq!(reported.first(), Some(&DeleteProgress::Collected(2)));
+		assert!(reported.contains(&DeleteProgress::Marked(1, 2)));
+		assert!(reported.contains(&DeleteProgress::Deleted(2, 2)));
+		assert!(reported.contains(&DeleteProgress::RemovedDirectories(0, 1)));
+
+		assert!(!root.join("Code.exe").exists());
+		assert!(!root.join("resources").exists());
+		assert!(root.join("_").join("Code.exe").exists());
+		assert!(root.join("unins000.dat").exists());
+
+		fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn delete_grace_separates_marking_from_closing() {
+		#[derive(Debug, PartialEq)]
+		enum Event {
+			Progress(DeleteProgress),
+			Slept(Duration),
+		}
+
+		thread_local! {
+			static EVENTS: RefCell<Vec<Event>> = const { RefCell::new(vec![]) };
+		}
+
+		fn sleep(duration: Duration) {
+			EVENTS.with(|events| events.borrow_mut().push(Event::Slept(duration)));
+		}
+
+		let root = fake_installation("delete-grace");
+
+		// far longer than the test could wait for
+		let options = UpdateOptions {
+			delete_grace: Duration::from_secs(60 * 60),
+			sleep,
+			..Default::default()
+		};
+
+		delete_existing_version(&logger(), &root, "_", &options, false, &|progress| {
+			EVENTS.with(|events| events.borrow_mut().push(Event::Progress(progress)))
+		})
+		.unwrap();
+
+		fs::remove_dir_all(&root).unwrap();
+
+		let events = EVENTS.with(|events| events.replace(vec![]));
+		let slept = events
+			.iter()
+			.position(|event| *event == Event::Slept(options.delete_grace))
+			.unwrap();
+
+		assert_eq!(
+			events
+				.iter()
+				.filter(|event| matches!(event, Event::Slept(_)))
+				.count(),
+			1
+		);
+		assert_eq!(
+			events[slept - 1],
+			Event::Progress(DeleteProgress::Marked(1, 2))
+		);
+		assert_eq!(
+			events[slept + 1],
+			Event::Progress(DeleteProgress::Deleted(0, 2))
+		);
+	}
+
+	#[test]
+	fn custom_uninstall_log_names_are_patched() {
+		let root = fake_installation("custom-uninst");
+		let root_str = root.to_str().unwrap();
+		let custom = root.join("unins001.dat");
+		let recs = vec![path_rec(&format!("{}\\_\\Code.exe", root_str))];
+		fs::write(&custom, log_bytes(recs)).unwrap();
+
+		let options = UpdateOptions {
+			uninst_name: Some(String::from("unins001.dat")),
+			..Default::default()
+		};
+
+		let (tx, rx) = mpsc::channel();
+		let ui_thread = thread::spawn(move || {
+			gui::run_progress_window(true, tx, String::from("Updating"));
+		});
+		let window = rx.recv().unwrap();
+
+		let result = do_update(&logger(), &root.join("Code.exe"), "_", &options, &window);
+		window.exit();
+		ui_thread.join().unwrap();
+
+		let patched = read_file(&custom).map(|(_, recs)| recs[0].get_paths().unwrap());
+		let default_log = fs::read(root.join("unins000.dat")).unwrap();
+		fs::remove_dir_all(&root).unwrap();
+
+		result.unwrap();
+		assert_eq!(patched.unwrap(), vec![format!("{}\\Code.exe", root_str)]);
+		assert_eq!(default_log, b"log");
+	}
+
+	#[test]
+	fn moved_updates_are_verified() {
+		let dir = temp_dir("verify-moved");
+		let update = dir.join("_");
+		fs::create_dir_all(dir.join("resources")).unwrap();
+		fs::write(dir.join("Code.exe"), b"exe").unwrap();
+
+		let names = [
+			std::ffi::OsString::from("Code.exe"),
+			std::ffi::OsString::from("resources"),
+		];
+		let landed = verify_moved(&dir, &names, &update);
+
+		// the current executable went missing in a move
+		fs::remove_file(dir.join("Code.exe")).unwrap();
+		let missing = verify_moved(&dir, &names, &update);
+
+		// the update folder was left behind
+		fs::create_dir(&update).unwrap();
+		let leftover = verify_moved(&dir, &names[1..], &update);
+
+		let _ = fs::remove_dir_all(&dir);
+
+		landed.unwrap();
+		assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+		assert!(leftover
+			.unwrap_err()
+			.to_string()
+			.contains("is still there after the update"));
+	}
+
+	#[test]
+	fn remaining_files_are_deleted_once_released() {
+		let dir = temp_dir("mark-remaining");
+		let sub = dir.join("resources").join("app");
+		fs::create_dir_all(&sub).unwrap();
+
+		let held = sub.join("main.js");
+		fs::write(&held, b"in use").unwrap();
+		fs::write(dir.join("Code.exe"), b"exe").unwrap();
+
+		// still open elsewhere, like a file whose delete wasn't finalized
+		let handle = fs::File::open(&held).unwrap();
+		mark_remaining_files(&logger(), &dir);
+		drop(handle);
+
+		let left = (held.exists(), dir.join("Code.exe").exists());
+		let removed = fs::remove_dir(&sub)
+			.and_then(|_| fs::remove_dir(dir.join("resources")))
+			.and_then(|_| fs::remove_dir(&dir));
+		let _ = fs::remove_dir_all(&dir);
+
+		assert_eq!(left, (false, false));
+		removed.unwrap();
+	}
+
+	#[test]
+	fn delete_existing_version_does_not_follow_junctions() {
+		use std::process::Command;
+
+		let root = fake_installation("delete-junction");
+		let target = temp_dir("delete-junction-target");
+		fs::write(target.join("keep.txt"), b"keep").unwrap();
+
+		let junction = root.join("resources").join("app").join("cache");
+		let status = Command::new("cmd")
+			.args(["/c", "mklink", "/J"])
+			.arg(&junction)
+			.arg(&target)
+			.status()
+			.unwrap();
+		assert!(status.success());
+
+		let result = delete_existing_version(
+			&logger(),
+			&root,
+			"_",
+			&UpdateOptions::default(),
+			false,
+			&|_| {},
+		);
+
+		let kept = target.join("keep.txt").exists();
+		let junction_left = fs::symlink_metadata(&junction).is_ok();
+		let _ = fs::remove_dir(&junction);
+		fs::remove_dir_all(&root).unwrap();
+		fs::remove_dir_all(&target).unwrap();
+
+		result.unwrap();
+		assert!(kept);
+		assert!(!junction_left);
+	}
+
+	#[test]
+	fn delete_existing_version_leaves_everything_if_a_file_is_in_use() {
+		use windows_sys::Win32::System::LibraryLoader::{
+			FreeLibrary, LoadLibraryExW, LOAD_LIBRARY_AS_IMAGE_RESOURCE,
+		};
+
+		let root = fake_installation("delete-in-use");
+
+		// map an image the way a running executable is; it is collected
+		// after Code.exe, which must get unmarked again
+		let dll = root.join("resources").join("app").join("node.dll");
+		fs::copy(std::env::current_exe().unwrap(), &dll).unwrap();
+
+		let module = unsafe {
+			LoadLibraryExW(
+				strings::to_u16s(dll.as_os_str()).as_ptr(),
+				0,
+				LOAD_LIBRARY_AS_IMAGE_RESOURCE,
+			)
+		};
+		assert_ne!(module, 0);
+
+		let result = delete_existing_version(
+			&logger(),
+			&root,
+			"_",
+			&UpdateOptions::default(),
+			false,
+			&|_| (),
+		);
+
+		unsafe {
+			FreeLibrary(module);
+		}
+
+		assert!(result.is_err());
+		assert!(root.join("Code.exe").exists());
+		assert!(root
+			.join("resources")
+			.join("app")
+			.join("package.json")
+			.exists());
+		assert!(dll.exists());
+
+		fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn pending_deletes_leave_the_installation_recoverable() {
+		let root = fake_installation("delete-pending");
+		let app = root.join("resources").join("app");
+		let package = app.join("package.json");
+
+		// a file read elsewhere can be marked, but its delete stays pending
+		// until the reader is done, so its folder can't be removed yet
+		let reader = fs::File::open(&package).unwrap();
+		let handles = open_file_handles(&logger(), std::slice::from_ref(&package), false).unwrap();
+		handles[0].mark_for_deletion().unwrap();
+		handles[0].close().unwrap();
+
+		let removed_while_read = fs::remove_dir(&app).is_ok();
+		drop(reader);
+
+		// the update is untouched, so running it again finishes the job
+		let result = delete_existing_version(
+			&logger(),
+			&root,
+			"_",
+			&UpdateOptions::default(),
+			false,
+			&|_| (),
+		);
+
+		let left = (
+			root.join("Code.exe").exists(),
+			root.join("resources").exists(),
+		);
+		let update = fs::read(root.join("_").join("Code.exe"));
+		let _ = fs::remove_dir_all(&root);
+
+		assert!(!removed_while_read);
+		result.unwrap();
+		assert_eq!(left, (false, false));
+		assert_eq!(update.unwrap(), b"new");
+	}
+
+	#[test]
+	fn delete_existing_version_times_out_without_deleting() {
+		let root = fake_installation("delete-timeout");
+		let options = UpdateOptions {
+			delete_timeout: Some(Duration::from_secs(0)),
+			..Default::default()
+		};
+
+		let err =
+			delete_existing_version(&logger(), &root, "_", &options, false, &|_| ()).unwrap_err();
+
+		assert_eq!(
+			err.downcast_ref::<io::Error>().map(|err| err.kind()),
+			Some(io::ErrorKind::TimedOut)
+		);
+		assert!(root.join("Code.exe").exists());
+		assert!(root
+			.join("resources")
+			.join("app")
+			.join("package.json")
+			.exists());
+
+		fs::remove_dir_all(&root).unwrap();
+	}
+
+	/**
+	 * Verifies `bytes` as the uninstall log of an installation of its own.
+	 */
+	fn verify_bytes(name: &str, bytes: &[u8]) -> Result<VerifyReport, Box<dyn error::Error>> {
+		let dir = temp_dir(name);
+		let path = dir.join("unins000.dat");
+		fs::write(&path, bytes).unwrap();
+
+		let result = verify(&path);
+		fs::remove_dir_all(&dir).unwrap();
+		result
+	}
+
+	/**
+	 * The `--parse` output for a log of `bytes`, one line per entry.
+	 */
+	fn parse_bytes(
+		name: &str,
+		bytes: &[u8],
+		options: &ParseOptions,
+	) -> Result<Vec<String>, Box<dyn error::Error>> {
+		let dir = temp_dir(name);
+		let path = dir.join("unins000.dat");
+		fs::write(&path, bytes).unwrap();
+
+		let mut output = vec![];
+		let result = parse_to_writer(&path, options, &mut output);
+		fs::remove_dir_all(&dir).unwrap();
+		result?;
+
+		Ok(String::from_utf8(output)
+			.unwrap()
+			.lines()
+			.map(String::from)
+			.collect())
+	}
+
+	#[test]
+	fn verify_passes_a_clean_log() {
+		let bytes = log_bytes(vec![path_rec("C:\\Code\\Code.exe"), rec(0x10, &[])]);
+		let report = verify_bytes("verify-clean", &bytes).unwrap();
+
+		assert_eq!(report.num_recs, 2);
+		assert!(report.warnings.is_empty());
+	}
+
+	#[test]
+	fn verify_warns_about_recoverable_corruption() {
+		let mut trailing = log_bytes(vec![path_rec("C:\\Code\\Code.exe")]);
+		trailing.extend_from_slice(&[0; 16]);
+
+		let unknown = log_bytes(vec![rec(0x99, &[])]);
+
+		// a high surrogate with nothing after it
+		let lone_surrogate = log_bytes(vec![rec(0x82, &[0x43, 0x3a, 0xd83d])]);
+
+		for &(name, ref bytes) in &[
+			("verify-trailing", trailing),
+			("verify-unknown", unknown),
+			("verify-surrogate", lone_surrogate),
+		] {
+			let report = verify_bytes(name, bytes).unwrap();
+			assert_eq!(report.num_recs, 1, "{}", name);
+			assert_eq!(report.warnings.len(), 1, "{}", name);
+		}
+	}
+
+	#[test]
+	fn verify_fails_on_fatal_corruption() {
+		let bytes = log_bytes(vec![path_rec("C:\\Code\\Code.exe")]);
+
+		let mut header_crc = bytes.clone();
+		header_crc[100] ^= 1;
+
+		// past the block's size, size complement and crc
+		let mut block_crc = bytes.clone();
+		block_crc[model::HEADER_SIZE + 20] ^= 1;
+
+		let truncated = bytes[..bytes.len() - 1].to_vec();
+
+		for &(name, ref bytes) in &[
+			("verify-header-crc", header_crc),
+			("verify-block-crc", block_crc),
+			("verify-truncated", truncated),
+		] {
+			assert!(verify_bytes(name, bytes).is_err(), "{}", name);
+		}
+	}
+
+	#[test]
+	fn open_file_handles_matches_opening_them_in_order() {
+		let dir = temp_dir("open-handles");
+		let paths: Vec<PathBuf> = (0..50).map(|i| dir.join(format!("file{}", i))).collect();
+
+		for path in &paths {
+			fs::write(path, b"").unwrap();
+		}
+
+		let file_handles = open_file_handles(&logger(), &paths, false).unwrap();
+		assert_eq!(file_handles.len(), paths.len());
+
+		// the handles at even positions must be those of the even files
+		for file_handle in file_handles.iter().step_by(2) {
+			file_handle.mark_for_deletion().unwrap();
+		}
+
+		drop(file_handles);
+
+		for (i, path) in paths.iter().enumerate() {
+			assert_eq!(path.exists(), i % 2 == 1, "{:?}", path);
+		}
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn open_file_handles_keeps_the_error_kind() {
+		let dir = temp_dir("open-handles-missing");
+		fs::write(dir.join("present"), b"").unwrap();
+
+		let paths = vec![dir.join("present"), dir.join("missing")];
+		let err = open_file_handles(&logger(), &paths, false)
+			.err()
+			.expect("a missing file can't be opened");
+
+		assert_eq!(
+			err.downcast_ref::<io::Error>().map(|err| err.kind()),
+			Some(io::ErrorKind::NotFound)
+		);
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn missing_files_fail_fast() {
+		let dir = temp_dir("open-handle-missing");
+
+		// 16 attempts would back off for well over a minute
+		let started = Instant::now();
+		let result = open_file_handle(&logger(), &dir.join("missing"), 16, false);
+		let elapsed = started.elapsed();
+		fs::remove_dir_all(&dir).unwrap();
+
+		assert_eq!(
+			result.err().map(|err| err.kind()),
+			Some(io::ErrorKind::NotFound)
+		);
+		assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+	}
+
+	#[test]
+	fn updaters_in_the_installation_are_detected() {
+		let root = Path::new("C:\\Code");
+		let inside = |exe: &str| is_in_update_tree(Path::new(exe), root);
+
+		assert!(inside("C:\\Code\\inno_updater.exe"));
+		assert!(inside("C:\\Code\\_\\tools\\inno_updater.exe"));
+		assert!(inside("C:\\Code\\resources\\app\\inno_updater.exe"));
+
+		// the folders which are left alone, and anywhere else
+		assert!(!inside("C:\\Code\\tools\\inno_updater.exe"));
+		assert!(!inside("C:\\Code\\bootstrap\\inno_updater.exe"));
+		assert!(!inside("C:\\Code2\\inno_updater.exe"));
+		assert!(!inside("C:\\Users\\me\\inno_updater.exe"));
+	}
+
+	#[test]
+	fn update_folder_names_must_be_one_component() {
+		assert!(check_update_folder_name("_").is_ok());
+
+		for name in &["", ".", "..", "a\\b", "a/b", "C:"] {
+			match check_update_folder_name(name) {
+				Err(err @ UpdateError::BadArguments(_)) => assert_eq!(err.exit_code(), 2),
+				result => panic!("{:?} was not rejected as bad arguments: {:?}", name, result),
+			}
+		}
+	}
+
+	#[test]
+	fn logs_are_written_back_with_their_block_size() {
+		let recs = || {
+			(0..500)
+				.map(|i| path_rec(&format!("C:\\Code\\resources\\app\\file{}.js", i)))
+				.collect::<Vec<FileRec>>()
+		};
+
+		for &block_size in &[blockio::DEFAULT_BLOCK_SIZE, 8192] {
+			let mut bytes = io::Cursor::new(vec![]);
+			write_to_writer_with_block_size(&mut bytes, &header(), recs(), block_size).unwrap();
+			let bytes = bytes.into_inner();
+
+			let (header, recs, observed) = read_from_reader_with_offsets(&bytes[..]).unwrap();
+			assert_eq!(observed, block_size);
+
+			let mut rewritten = io::Cursor::new(vec![]);
+			write_to_writer_with_block_size(
+				&mut rewritten,
+				&header,
+				recs.into_iter().map(|(_, rec)| rec).collect(),
+				observed,
+			)
+			.unwrap();
+
+			assert_eq!(rewritten.into_inner(), bytes);
+		}
+	}
+
+	/**
+	 * Stands in for a log past 4GB without writing one: every write after
+	 * the header moves the position on by another 4GB.
+	 */
+	struct SparseWriter {
+		position: u64,
+	}
+
+	impl Write for SparseWriter {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			if self.position >= model::HEADER_SIZE as u64 {
+				self.position += u32::MAX as u64;
+			}
+
+			self.position += buf.len() as u64;
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	impl Seek for SparseWriter {
+		fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+			self.position = match pos {
+				io::SeekFrom::Start(offset) => offset,
+				io::SeekFrom::Current(offset) => self.position.wrapping_add(offset as u64),
+				io::SeekFrom::End(_) => return Err(io::Error::other("no end to seek from")),
+			};
+
+			Ok(self.position)
+		}
+	}
+
+	#[test]
+	fn logs_past_32_bits_are_refused() {
+		let mut writer = SparseWriter { position: 0 };
+		let err = write_to_writer(&mut writer, &header(), vec![path_rec("C:\\Code\\Code.exe")])
+			.unwrap_err();
+
+		assert!(writer.position > u32::MAX as u64);
+		assert_eq!(
+			err.to_string(),
+			"Header write error: End offset does not fit in 32 bits"
+		);
+	}
+
+	#[test]
+	fn failed_writes_leave_the_log_intact() {
+		let dir = temp_dir("failed-write");
+		let path = dir.join("unins000.dat");
+		let original = log_bytes(vec![path_rec("C:\\Code\\Code.exe")]);
+		fs::write(&path, &original).unwrap();
+
+		// the temp file can't be created where a directory already is
+		fs::create_dir(dir.join("unins000.dat.tmp")).unwrap();
+
+		let recs = vec![path_rec("C:\\Code\\_\\Code.exe")];
+		assert!(write_file(&path, &header(), recs, blockio::DEFAULT_BLOCK_SIZE).is_err());
+		assert_eq!(fs::read(&path).unwrap(), original);
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn interrupted_writes_leave_the_log_intact() {
+		let dir = temp_dir("interrupted-write");
+		let path = dir.join("unins000.dat");
+		let original = log_bytes(vec![path_rec("C:\\Code\\Code.exe")]);
+		fs::write(&path, &original).unwrap();
+
+		let rewritten = log_bytes(vec![path_rec("C:\\Code\\_\\Code.exe")]);
+		let partial = &rewritten[..rewritten.len() / 2];
+
+		// failing halfway through, or stopping there without noticing
+		let failed = replace_through_temp_file(&path, |temp_path| {
+			fs::write(temp_path, partial)?;
+			Err(io::Error::other("disk full").into())
+		});
+		let truncated = replace_through_temp_file(&path, |temp_path| {
+			fs::write(temp_path, partial).map_err(|err| err.into())
+		});
+
+		let contents = fs::read(&path).unwrap();
+		let temp_left = dir.join("unins000.dat.tmp").exists();
+		fs::remove_dir_all(&dir).unwrap();
+
+		assert_eq!(failed.unwrap_err().to_string(), "disk full");
+		assert!(truncated.is_err());
+		assert_eq!(contents, original);
+		assert!(!temp_left);
+	}
+
+	#[test]
+	fn dedup_recs_drops_repeated_path_sets() {
+		let recs = vec![
+			paths_rec(0x82, &["C:\\a", "C:\\b"]),
+			paths_rec(0x81, &["C:\\dir"]),
+			rec(0x20, &[]),
+			// the same set in another order
+			paths_rec(0x82, &["C:\\b", "C:\\a"]),
+			// overlapping, but not the same set
+			paths_rec(0x82, &["C:\\a", "C:\\c"]),
+			paths_rec(0x81, &["C:\\dir"]),
+			// the same paths, but of another type
+			paths_rec(0x81, &["C:\\a", "C:\\b"]),
+		];
+
+		let kept: Vec<(u16, Vec<String>)> = dedup_recs(recs)
+			.unwrap()
+			.iter()
+			.map(|rec| {
+				let paths = if rec.is_path_record() {
+					rec.get_paths().unwrap()
+				} else {
+					vec![]
+				};
+
+				(rec.typ.value(), paths)
+			})
+			.collect();
+
+		let paths = |paths: &[&str]| paths.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+
+		// the first directory record and the last file record are kept
+		assert_eq!(
+			kept,
+			vec![
+				(0x81, paths(&["C:\\dir"])),
+				(0x20, vec![]),
+				(0x82, paths(&["C:\\b", "C:\\a"])),
+				(0x82, paths(&["C:\\a", "C:\\c"])),
+				(0x81, paths(&["C:\\a", "C:\\b"])),
+			]
+		);
+	}
+
+	#[test]
+	fn file_rec_reader_streams_the_fixture() {
+		let fixture = Path::new(env!("CARGO_MANIFEST_DIR"))
+			.join("tests")
+			.join("fixtures")
+			.join("unins000.dat");
+		let mut input = io::BufReader::new(fs::File::open(fixture).unwrap());
+		let header = Header::from_reader(&mut input).unwrap();
+
+		let mut reader = FileRecReader::new(&mut input, &header);
+		let mut count = 0;
+
+		for rec in reader.by_ref() {
+			rec.unwrap();
+			count += 1;
+		}
+
+		assert_eq!(count, 7);
+		assert_eq!(count, header.num_recs);
+		assert!(reader.next().is_none());
+	}
+
+	#[test]
+	fn file_rec_reader_stops_after_an_error() {
+		let mut bytes = log_bytes(vec![path_rec("C:\\a"), path_rec("C:\\b")]);
+
+		// break the first block's crc
+		bytes[model::HEADER_SIZE + 8] ^= 0xff;
+
+		let mut input = &bytes[..];
+		let header = Header::from_reader(&mut input).unwrap();
+		let results: Vec<bool> = FileRecReader::new(&mut input, &header)
+			.map(|rec| rec.is_ok())
+			.collect();
+
+		assert_eq!(results, vec![false]);
+	}
+
+	#[test]
+	fn dump_matches_the_golden_file() {
+		let fixtures = Path::new(env!("CARGO_MANIFEST_DIR"))
+			.join("tests")
+			.join("fixtures");
+
+		let mut output = vec![];
+		dump_to_writer(&fixtures.join("unins000.dat"), &mut output).unwrap();
+
+		// in case the golden file was checked out with CRLF line endings
+		let expected = fs::read_to_string(fixtures.join("unins000.dump")).unwrap();
+		assert_eq!(
+			String::from_utf8(output).unwrap(),
+			expected.replace("\r\n", "\n")
+		);
+	}
+
+	#[test]
+	fn record_offsets_grow_by_their_lengths() {
+		let bytes = log_bytes(vec![
+			rec(0x10, &[]),
+			path_rec("C:\\Code\\Code.exe"),
+			paths_rec(0x81, &["C:\\Code\\resources", "C:\\Code"]),
+			rec(0x11, &[]),
+		]);
+
+		let (_, recs, _) = read_from_reader_with_offsets(&bytes[..]).unwrap();
+		let mut expected = 0;
+
+		for &(offset, ref rec) in &recs {
+			assert_eq!(offset, expected);
+			expected += rec.encoded_len() as u64;
+		}
+
+		let options = ParseOptions {
+			offsets: true,
+			..Default::default()
+		};
+		let lines = parse_bytes("parse-offsets", &bytes, &options).unwrap();
+
+		for (i, &(offset, ref rec)) in recs.iter().enumerate() {
+			let line = format!(
+				"Record {} at offset {} ({} bytes)",
+				i,
+				offset,
+				rec.encoded_len()
+			);
+			assert!(
+				lines.iter().any(|l| l.starts_with(&line)),
+				"missing {:?}",
+				line
+			);
+		}
+	}
+
+	#[test]
+	fn parse_tells_the_architecture_from_the_header_id() {
+		let log = |id: &str| {
+			let mut bytes = io::Cursor::new(vec![]);
+			write_to_writer(&mut bytes, &header_with_id(id), vec![rec(0x10, &[])]).unwrap();
+			bytes.into_inner()
+		};
+		let expect = |arch: &str| ParseOptions {
+			expect_arch: Some(String::from(arch)),
+			..Default::default()
+		};
+
+		let x86 = log("Inno Setup Uninstall Log (b)");
+		let x64 = log("Inno Setup Uninstall Log (b) 64-bit");
+
+		let lines = parse_bytes("parse-arch-x86", &x86, &expect("x86")).unwrap();
+		assert!(lines.contains(&String::from("Architecture: x86")));
+
+		let lines = parse_bytes("parse-arch-x64", &x64, &expect("x64")).unwrap();
+		assert!(lines.contains(&String::from("Architecture: x64")));
+
+		let err = parse_bytes("parse-arch-mismatch", &x86, &expect("x64")).unwrap_err();
+		assert_eq!(err.to_string(), "Expected a x64 uninstall log, found x86");
+	}
+
+	#[test]
+	fn grep_prints_matching_records_ignoring_case() {
+		let bytes = log_bytes(vec![
+			path_rec("C:\\Code\\Code.exe"),
+			paths_rec(0x81, &["C:\\Code\\resources\\APP", "C:\\Code\\resources"]),
+			path_rec("C:\\Code\\unins000.exe"),
+		]);
+
+		let options = ParseOptions {
+			grep: Some(String::from("\\app")),
+			..Default::default()
+		};
+		let lines = parse_bytes("parse-grep", &bytes, &options).unwrap();
+		let matches: Vec<&String> = lines.iter().filter(|l| l.starts_with("1: ")).collect();
+
+		// every path of a matching record, and nothing of the others
+		assert_eq!(
+			matches,
+			vec!["1: C:\\Code\\resources\\APP", "1: C:\\Code\\resources"]
+		);
+		assert!(!lines
+			.iter()
+			.any(|l| l.starts_with("0: ") || l.starts_with("2: ")));
+	}
+
+	#[test]
+	fn flags_are_printed_inline_with_paths() {
+		let bytes = log_bytes(vec![
+			path_rec("C:\\Code\\Code.exe").with_extra_data(0x101),
+			paths_rec(0x81, &["C:\\Code\\resources"]).with_extra_data(0x20),
+		]);
+
+		let options = ParseOptions {
+			flags: true,
+			..Default::default()
+		};
+		let lines = parse_bytes("parse-flags", &bytes, &options).unwrap();
+
+		// bits without a name stay in hex
+		assert!(lines.contains(&String::from(
+			"0: [0x101 ExistedBeforeInstall|RemoveReadOnly] C:\\Code\\Code.exe"
+		)));
+		assert!(lines.contains(&String::from("1: [0x20] C:\\Code\\resources")));
+	}
+
+	#[test]
+	fn prometheus_metrics_count_each_present_type() {
+		let bytes = log_bytes(vec![
+			path_rec("C:\\Code\\Code.exe"),
+			paths_rec(0x81, &["C:\\Code\\resources"]),
+			path_rec("C:\\Code\\unins000.exe"),
+		]);
+
+		let options = ParseOptions {
+			prometheus: true,
+			..Default::default()
+		};
+		let lines = parse_bytes("parse-prometheus", &bytes, &options).unwrap();
+
+		assert_eq!(
+			lines,
+			vec![
+				"# HELP inno_uninstall_records Number of uninstall log records by type.",
+				"# TYPE inno_uninstall_records gauge",
+				"inno_uninstall_records{type=\"DeleteDirOrFiles\"} 1",
+				"inno_uninstall_records{type=\"DeleteFile\"} 2",
+			]
+		);
+	}
+
+	#[test]
+	fn check_encoding_flags_byte_swapped_paths() {
+		let swapped: Vec<u16> = "C:\\Code\\Code.exe"
+			.encode_utf16()
+			.map(u16::swap_bytes)
+			.collect();
+		let bytes = log_bytes(vec![
+			path_rec("C:\\Code\\Code.exe"),
+			rec(0x82, &swapped),
+			path_rec("C:\\Code\\r\u{e9}sum\u{e9}.txt"),
+		]);
+
+		let options = ParseOptions {
+			check_encoding: true,
+			..Default::default()
+		};
+		let lines = parse_bytes("parse-encoding", &bytes, &options).unwrap();
+		let flagged: Vec<&String> = lines
+			.iter()
+			.filter(|line| line.contains("may be misencoded"))
+			.collect();
+
+		assert_eq!(flagged.len(), 1, "{:?}", flagged);
+		assert!(flagged[0].starts_with("Record 1 "), "{}", flagged[0]);
+	}
+
+	#[test]
+	fn repaired_logs_pass_strict_reads() {
+		let dir = temp_dir("repair-crc");
+		let input = dir.join("broken.dat");
+		let output = dir.join("unins000.dat");
+
+		let recs = vec![
+			path_rec("C:\\Code\\Code.exe"),
+			paths_rec(0x81, &["C:\\Code\\resources"]),
+		];
+		let mut bytes = log_bytes(recs.clone());
+
+		// the header crc, then the first block's, which follows its size
+		// and the size's complement
+		bytes[model::HEADER_SIZE - 4] ^= 0xff;
+		bytes[model::HEADER_SIZE + 8] ^= 0xff;
+		fs::write(&input, &bytes).unwrap();
+
+		let broken = read_file(&input).map(|_| ());
+		let repaired = repair_crc(&input, &output);
+		let read = read_file(&output);
+		fs::remove_dir_all(&dir).unwrap();
+
+		assert!(broken.is_err());
+		assert_eq!(repaired.unwrap(), 2);
+		assert!(read.unwrap().1 == recs);
+	}
+
+	#[test]
+	fn relative_to_strips_the_root_off_paths() {
+		let bytes = log_bytes(vec![
+			path_rec("C:\\Code\\Code.exe"),
+			paths_rec(0x81, &["c:\\code", "C:\\Code2\\data"]),
+		]);
+
+		let options = ParseOptions {
+			flags: true,
+			relative_to: Some(String::from("C:\\Code\\")),
+			..Default::default()
+		};
+		let lines = parse_bytes("parse-relative", &bytes, &options).unwrap();
+		let paths: Vec<&str> = lines
+			.iter()
+			.filter_map(|line| line.split("] ").nth(1))
+			.collect();
+
+		assert_eq!(paths, vec!["Code.exe", ".", "(outside) C:\\Code2\\data"]);
+	}
+
+	#[test]
+	fn manifest_lists_crcs_and_missing_files() {
+		let root = temp_dir("manifest");
+		fs::create_dir(root.join("resources")).unwrap();
+		fs::write(root.join("Code.exe"), b"exe").unwrap();
+
+		let paths: Vec<String> = ["Code.exe", "resources", "gone.txt"]
+			.iter()
+			.map(|p| root.join(p).to_str().unwrap().to_owned())
+			.collect();
+		let recs: Vec<(u64, FileRec)> = paths.iter().map(|p| (0, path_rec(p))).collect();
+
+		// the log's own root is elsewhere, so the paths are kept as they are
+		let log_path = Path::new("C:\\Old\\unins000.dat");
+		let manifest = |json: bool| {
+			let mut output = vec![];
+			print_manifest(log_path, &recs, &root, json, &mut output).unwrap();
+			String::from_utf8(output).unwrap()
+		};
+		let text = manifest(false);
+		let json = manifest(true);
+		fs::remove_dir_all(&root).unwrap();
+
+		let crc = blockio::CRC32.checksum(b"exe");
+		assert_eq!(
+			text,
+			format!("{:08x} {}\nmissing  {}\n", crc, paths[0], paths[2])
+		);
+		assert_eq!(
+			json,
+			format!(
+				"[{{\"path\": {}, \"crc\": {}}}, {{\"path\": {}, \"missing\": true}}]\n",
+				strings::to_json_string(&paths[0]),
+				crc,
+				strings::to_json_string(&paths[2])
+			)
+		);
+	}
+
+	#[test]
+	fn repair_header_only_fixes_the_header_crc() {
+		let dir = temp_dir("repair-header");
+		let path = dir.join("unins000.dat");
+		let bytes = log_bytes(vec![path_rec("C:\\Code\\Code.exe")]);
+
+		let mut broken = bytes.clone();
+		broken[model::HEADER_SIZE - 4] ^= 0xff;
+		fs::write(&path, &broken).unwrap();
+
+		let before = read_file(&path).map(|_| ());
+		let repaired = repair_header(&path).unwrap();
+		let repaired_again = repair_header(&path).unwrap();
+		let after = fs::read(&path).unwrap();
+		let read = read_file(&path).map(|_| ());
+		fs::remove_dir_all(&dir).unwrap();
+
+		assert!(before.is_err());
+		assert!(repaired);
+		assert!(!repaired_again);
+		assert_eq!(after, bytes);
+		read.unwrap();
+	}
+
+	#[test]
+	fn truncated_logs_are_reported() {
+		let fixture = Path::new(env!("CARGO_MANIFEST_DIR"))
+			.join("tests")
+			.join("fixtures")
+			.join("unins000.dat");
+		let bytes = fs::read(fixture).unwrap();
+		let truncated = &bytes[..bytes.len() - 10];
+
+		let err = verify_bytes("verify-truncated", truncated).err().unwrap();
+		assert_eq!(
+			err.to_string(),
+			format!("File is 10 bytes shorter than end offset {}", bytes.len())
+		);
+	}
+
+	#[test]
+	fn strings_are_grouped_by_record_type() {
+		let bytes = log_bytes(vec![
+			path_rec("C:\\Code\\Code.exe"),
+			paths_rec(0x89, &["HKCU", "Software\\Classes\\.txt", "VSCode"]),
+			paths_rec(0x84, &["C:\\Code\\code.ini", "Settings", "Theme"]),
+			paths_rec(0x20, &["bytecode"]),
+			paths_rec(0x89, &["HKCU", "Software\\Classes\\.md", "VSCode"]),
+		]);
+
+		let options = ParseOptions {
+			strings: true,
+			..Default::default()
+		};
+		let lines = parse_bytes("parse-strings", &bytes, &options).unwrap();
+		let start = lines.iter().position(|line| line == "DeleteFile:").unwrap();
+
+		// compiled code isn't decoded
+		assert_eq!(
+			lines[start..start + 7],
+			[
+				"DeleteFile:",
+				"\t0: C:\\Code\\Code.exe",
+				"IniDeleteEntry:",
+				"\t2: C:\\Code\\code.ini, Settings, Theme",
+				"RegDeleteValue:",
+				"\t1: HKCU, Software\\Classes\\.txt, VSCode",
+				"\t4: HKCU, Software\\Classes\\.md, VSCode",
+			]
+		);
+	}
+
+	#[test]
+	fn check_size_warns_about_trailing_data() {
+		let bytes = log_bytes(vec![path_rec("C:\\Code\\Code.exe")]);
+		let options = ParseOptions {
+			check_size: true,
+			..Default::default()
+		};
+		let is_warning = |line: &String| line.starts_with("Warning: ");
+
+		let lines = parse_bytes("parse-size", &bytes, &options).unwrap();
+		assert!(!lines.iter().any(is_warning));
+
+		let padded = [&bytes[..], &[0; 16]].concat();
+		let lines = parse_bytes("parse-size-padded", &padded, &options).unwrap();
+		let warning = format!(
+			"Warning: 16 trailing bytes after end offset {}",
+			bytes.len()
+		);
+		assert!(lines.contains(&warning), "{:?}", lines);
+	}
+
+	#[test]
+	fn check_delete_reports_files_which_cannot_be_deleted() {
+		use std::process::Command;
+
+		let root = temp_dir("check-delete-root");
+		let locked_dir = root.join("locked");
+		let locked = locked_dir.join("data.bin");
+		fs::create_dir(&locked_dir).unwrap();
+		fs::write(root.join("Code.exe"), b"exe").unwrap();
+		fs::write(&locked, b"data").unwrap();
+
+		// deny deleting the file, both directly and through its directory
+		let icacls = |path: &Path, arg: &str, grant: &str| {
+			let status = Command::new("icacls")
+				.arg(path)
+				.args([arg, grant])
+				.status()
+				.unwrap();
+			assert!(status.success());
+		};
+		icacls(&locked, "/deny", "*S-1-1-0:(DE)");
+		icacls(&locked_dir, "/deny", "*S-1-1-0:(DC)");
+
+		let paths: Vec<String> = ["Code.exe", "locked\\data.bin", "gone.txt"]
+			.iter()
+			.map(|p| root.join(p).to_str().unwrap().to_owned())
+			.collect();
+		let recs = paths.iter().map(|p| path_rec(p)).collect();
+		let options = ParseOptions {
+			check_delete: Some(root.clone()),
+			..Default::default()
+		};
+
+		let lines = parse_bytes("check-delete", &log_bytes(recs), &options);
+
+		icacls(&locked_dir, "/remove:d", "*S-1-1-0");
+		icacls(&locked, "/remove:d", "*S-1-1-0");
+		fs::remove_dir_all(&root).unwrap();
+
+		let lines = lines.unwrap();
+		let reported: Vec<&String> = lines
+			.iter()
+			.filter(|line| line.contains("cannot delete"))
+			.collect();
+		assert_eq!(
+			reported,
+			vec![&format!("Record 1: cannot delete {}", paths[1])]
+		);
+	}
+}
@@ -0,0 +1,192 @@
+/*-----------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *----------------------------------------------------------------------------------------*/
+
+use std::collections::LinkedList;
+use std::path::{Path, PathBuf};
+use std::{fs, io, mem, ptr};
+use strings::{from_utf16, to_u16s};
+
+// how many files to register with a single Restart Manager session
+const BATCH_SIZE: usize = 64;
+
+pub struct Locker {
+	pub name: String,
+	pub id: u32,
+}
+
+pub struct LockedFile {
+	pub path: PathBuf,
+	pub lockers: Vec<Locker>,
+}
+
+fn rm_error(action: &str, code: u32) -> io::Error {
+	io::Error::other(format!(
+		"Failed to {}: {}",
+		action,
+		io::Error::from_raw_os_error(code as i32)
+	))
+}
+
+/**
+ * Asks the Restart Manager which processes have any of `paths` open.
+ */
+fn get_lockers(paths: &[PathBuf]) -> Result<Vec<Locker>, io::Error> {
+	use windows_sys::core::PWSTR;
+	use windows_sys::Win32::Foundation::{ERROR_MORE_DATA, ERROR_SUCCESS};
+	use windows_sys::Win32::System::RestartManager::{
+		RmEndSession, RmGetList, RmRegisterResources, RmStartSession, CCH_RM_SESSION_KEY,
+		RM_PROCESS_INFO,
+	};
+
+	let wide_paths: Vec<Vec<u16>> = paths.iter().map(|p| to_u16s(p.as_os_str())).collect();
+	let raw_paths: Vec<PWSTR> = wide_paths.iter().map(|p| p.as_ptr() as PWSTR).collect();
+
+	unsafe {
+		let mut session: u32 = 0;
+		let mut key = [0u16; CCH_RM_SESSION_KEY as usize + 1];
+
+		let result = RmStartSession(&mut session, 0, key.as_mut_ptr());
+		if result != ERROR_SUCCESS {
+			return Err(rm_error("start a Restart Manager session", result));
+		}
+
+		let result = RmRegisterResources(
+			session,
+			raw_paths.len() as u32,
+			raw_paths.as_ptr(),
+			0,
+			ptr::null(),
+			0,
+			ptr::null(),
+		);
+
+		if result != ERROR_SUCCESS {
+			RmEndSession(session);
+			return Err(rm_error("register files with the Restart Manager", result));
+		}
+
+		let mut infos: Vec<RM_PROCESS_INFO> = vec![mem::zeroed(); 16];
+
+		loop {
+			let mut needed: u32 = 0;
+			let mut count = infos.len() as u32;
+			let mut reasons: u32 = 0;
+
+			let result = RmGetList(
+				session,
+				&mut needed,
+				&mut count,
+				infos.as_mut_ptr(),
+				&mut reasons,
+			);
+
+			if result == ERROR_MORE_DATA {
+				infos = vec![mem::zeroed(); needed as usize];
+				continue;
+			}
+
+			RmEndSession(session);
+
+			if result != ERROR_SUCCESS {
+				return Err(rm_error("list processes using files", result));
+			}
+
+			infos.truncate(count as usize);
+			break;
+		}
+
+		infos
+			.iter()
+			.map(|info| {
+				Ok(Locker {
+					name: from_utf16(&info.strAppName)?,
+					id: info.Process.dwProcessId,
+				})
+			})
+			.collect()
+	}
+}
+
+fn collect_files(root: &Path) -> Result<Vec<PathBuf>, io::Error> {
+	let mut directories: LinkedList<PathBuf> = LinkedList::new();
+	let mut files: Vec<PathBuf> = vec![];
+
+	directories.push_back(PathBuf::from(root));
+
+	while let Some(dir) = directories.pop_front() {
+		for entry in fs::read_dir(&dir)? {
+			let entry = entry?;
+			let entry_file_type = entry.file_type()?;
+
+			if entry_file_type.is_dir() {
+				directories.push_back(entry.path());
+			} else if entry_file_type.is_file() {
+				files.push(entry.path());
+			}
+		}
+	}
+
+	Ok(files)
+}
+
+/**
+ * Finds all files under `root` which other processes currently have open.
+ * Files are checked in batches, and only the batches with lockers are
+ * narrowed down file by file.
+ */
+pub fn find_locked_files(root: &Path) -> Result<Vec<LockedFile>, io::Error> {
+	let files = collect_files(root)?;
+	let mut result: Vec<LockedFile> = vec![];
+
+	for batch in files.chunks(BATCH_SIZE) {
+		if get_lockers(batch)?.is_empty() {
+			continue;
+		}
+
+		for path in batch {
+			let lockers = get_lockers(std::slice::from_ref(path))?;
+
+			if !lockers.is_empty() {
+				result.push(LockedFile {
+					path: path.clone(),
+					lockers,
+				});
+			}
+		}
+	}
+
+	Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::fs::File;
+
+	#[test]
+	fn files_held_open_are_reported_with_their_holder() {
+		let root = std::env::temp_dir().join(format!("inno-updater-locks-{}", std::process::id()));
+		let _ = fs::remove_dir_all(&root);
+		fs::create_dir_all(root.join("resources")).unwrap();
+
+		let held = root.join("resources").join("held.txt");
+		fs::write(&held, b"held").unwrap();
+		fs::write(root.join("free.txt"), b"free").unwrap();
+
+		let handle = File::open(&held).unwrap();
+		let locked = find_locked_files(&root).unwrap();
+		drop(handle);
+
+		assert_eq!(locked.len(), 1);
+		assert_eq!(locked[0].path, held);
+		assert!(locked[0]
+			.lockers
+			.iter()
+			.any(|locker| locker.id == std::process::id()));
+
+		assert!(find_locked_files(&root).unwrap().is_empty());
+		fs::remove_dir_all(&root).unwrap();
+	}
+}
@@ -4,501 +4,598 @@
  *----------------------------------------------------------------------------------------*/
 
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
+// rustfmt indents with hard tabs, doc comments inside impls included
+#![allow(clippy::tabs_in_doc_comments)]
 
-extern crate byteorder;
-extern crate crc;
+extern crate inno_updater;
 #[macro_use]
 extern crate slog;
 extern crate slog_async;
 extern crate slog_term;
-extern crate windows_sys;
-
-mod blockio;
-mod gui;
-mod handle;
-mod model;
-mod process;
-mod resources;
-mod strings;
-mod util;
-
-use handle::FileHandle;
-use model::{FileRec, Header};
+
+use inno_updater::messages::t;
+use inno_updater::{
+	gui, jsonlog, process, util, ArgumentError, ParseOptions, UpdateError, UpdateOptions,
+	VerifyReport, VERSION,
+};
 use slog::Drain;
-use std::collections::LinkedList;
-use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use std::vec::Vec;
-use std::{env, error, fmt, fs, io, thread};
-
-const VERSION: &str = env!("CARGO_PKG_VERSION");
-
-fn read_file(path: &Path) -> Result<(Header, Vec<FileRec>), Box<dyn error::Error>> {
-	let input_file = fs::File::open(path)?;
-	let mut input = io::BufReader::new(input_file);
-
-	let header = Header::from_reader(&mut input)?;
-	let mut reader = blockio::BlockRead::new(&mut input);
-	let mut recs = Vec::with_capacity(header.num_recs);
-
-	for _ in 0..header.num_recs {
-		recs.push(FileRec::from_reader(&mut reader)?);
-	}
-
-	Ok((header, recs))
+use std::{env, error, fs, io, thread};
+
+/**
+ * The exit codes of all commands, so that scripts can tell bad arguments,
+ * failed updates and corrupt uninstall logs apart.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(i32)]
+enum ExitCode {
+	Success = 0,
+	// any failure without a code of its own
+	Failure = 1,
+	// bad usage, options which don't parse, or an update folder name
+	// which isn't one
+	BadArguments = 2,
+	// an update failed without a code of its own
+	UpdateFailed = 3,
+	// an uninstall log is corrupt: a bad header, block or record
+	ParseError = 4,
+	PermissionDenied = 5,
+	ProcessKillFailed = 6,
+	DeleteFailed = 7,
+	RenameFailed = 8,
+	// --verify found problems which still let the log be read
+	VerifyWarnings = 9,
+	// --wait-only or a strict wait gave up before the processes exited, or
+	// --delete-timeout before the existing version was deleted
+	TimedOut = 10,
 }
 
-fn write_file(
-	path: &Path,
-	header: &Header,
-	recs: Vec<FileRec>,
-) -> Result<(), Box<dyn error::Error>> {
-	let mut output_file = fs::File::create(path)?;
-
-	// skip header
-	output_file.seek(io::SeekFrom::Start(448))?;
+impl ExitCode {
+	fn exit(self) -> ! {
+		std::process::exit(self as i32)
+	}
 
-	{
-		let mut output = io::BufWriter::new(&output_file);
-		let mut writer = blockio::BlockWrite::new(&mut output);
+	fn from_error(err: &(dyn error::Error + 'static)) -> ExitCode {
+		if err.is::<ArgumentError>() {
+			return ExitCode::BadArguments;
+		}
 
-		for rec in recs {
-			rec.to_writer(&mut writer)?;
+		if inno_updater::is_parse_error(err) {
+			return ExitCode::ParseError;
 		}
 
-		writer.flush()?;
+		match err.downcast_ref::<UpdateError>() {
+			Some(&UpdateError::BadArguments(_)) => ExitCode::BadArguments,
+			Some(UpdateError::ProcessKillFailed(err)) if timed_out(err.as_ref()) => {
+				ExitCode::TimedOut
+			}
+			Some(&UpdateError::ProcessKillFailed(_)) => ExitCode::ProcessKillFailed,
+			Some(UpdateError::DeleteFailed(err)) if timed_out(err.as_ref()) => ExitCode::TimedOut,
+			Some(&UpdateError::DeleteFailed(_)) => ExitCode::DeleteFailed,
+			Some(&UpdateError::RenameFailed { .. }) => ExitCode::RenameFailed,
+			Some(&UpdateError::PermissionDenied(_)) => ExitCode::PermissionDenied,
+			Some(&UpdateError::Other(_)) => ExitCode::UpdateFailed,
+			None => ExitCode::Failure,
+		}
 	}
 
-	let mut header = header.clone();
-
-	// what's the full file size?
-	let end_offset = output_file.seek(io::SeekFrom::Current(0))?;
-	header.end_offset = end_offset as u32;
-
-	// go back to beginning
-	output_file.seek(io::SeekFrom::Start(0))?;
-
-	let mut output = io::BufWriter::new(&output_file);
-	header.to_writer(&mut output)?;
-
-	output.flush()?;
+	/**
+	 * The exit code of `--verify`: corruption which keeps the log from
+	 * being read fails, while findings which still let it be used are
+	 * only warnings.
+	 */
+	fn from_verify(result: &Result<VerifyReport, Box<dyn error::Error>>) -> ExitCode {
+		match *result {
+			Ok(ref report) if report.warnings.is_empty() => ExitCode::Success,
+			Ok(_) => ExitCode::VerifyWarnings,
+			Err(ref err) => ExitCode::from_error(err.as_ref()),
+		}
+	}
+}
 
-	Ok(())
+/**
+ * Whether an update step failed by running out of time, rather than
+ * through an error of its own.
+ */
+fn timed_out(err: &(dyn error::Error + 'static)) -> bool {
+	err.downcast_ref::<io::Error>()
+		.is_some_and(|err| err.kind() == io::ErrorKind::TimedOut)
 }
 
-fn delete_existing_version(
+fn _main(
 	log: &slog::Logger,
-	root_path: &Path,
-	update_folder_name: &str,
+	args: &[String],
+	options: &UpdateOptions,
 ) -> Result<(), Box<dyn error::Error>> {
-	let mut directories: LinkedList<PathBuf> = LinkedList::new();
-	let mut top_directories: LinkedList<PathBuf> = LinkedList::new();
-	let mut file_handles: LinkedList<FileHandle> = LinkedList::new();
-
-	let root = PathBuf::from(root_path);
-	directories.push_back(root);
-
-	while let Some(dir) = directories.pop_front() {
-		info!(log, "Reading directory: {:?}", dir);
-
-		for entry in fs::read_dir(&dir)? {
-			let entry = entry?;
-			let entry_name = entry.file_name();
-			let entry_name = entry_name
-				.to_str()
-				.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Could not get entry name"))?;
-
-			if dir == root_path {
-				// don't delete the update folder
-				if entry_name == update_folder_name {
-					continue;
-				}
-
-				// don't delete ourselves
-				if entry_name == "tools" {
-					continue;
-				}
-
-				// don't delete any of the unins* files
-				if entry_name.starts_with("unins") {
-					continue;
-				}
-
-				// don't delete the sparse package folder
-				if entry_name == "appx" {
-					continue;
-				}
-
-				// don't delete the bootstrap folder
-				if (entry_name == "bootstrap") {
-					continue;
-				}
-			}
-
-			let entry_file_type = entry.file_type()?;
-			let entry_path = entry.path();
+	info!(log, "Starting: {}, {}, {}", args[1], args[2], args[3]);
 
-			if entry_file_type.is_dir() {
-				if dir == root_path {
-					top_directories.push_back(entry_path.to_owned());
-				}
+	let code_path = PathBuf::from(&args[1]);
 
-				directories.push_back(entry_path);
-			} else if entry_file_type.is_file() {
-				// attempt to get exclusive file handle
-				let msg = format!("Opening file handle: {:?}", entry_path);
-				let file_handle = util::retry(
-					&msg,
-					|attempt| -> Result<FileHandle, Box<dyn error::Error>> {
-						info!(
-							log,
-							"Get file handle: {:?} (attempt {})", entry_path, attempt
-						);
-
-						FileHandle::new(&entry_path)
-					},
-					Some(16),
-				)?;
-
-				file_handles.push_back(file_handle);
-			}
-		}
+	if !code_path.is_absolute() {
+		return Err(ArgumentError(format!(
+			"Code path needs to be absolute. Instead got: {}",
+			args[1]
+		))
+		.into());
 	}
 
-	info!(log, "Collected all directories and file handles");
-
-	for file_handle in &file_handles {
-		util::retry(
-			"marking a file for deletion",
-			|_| -> Result<(), Box<dyn error::Error>> { file_handle.mark_for_deletion() },
-			None,
-		)?;
+	if !code_path.exists() {
+		return Err(ArgumentError(format!("Code path doesn't seem to exist: {}", args[1])).into());
 	}
 
-	info!(log, "All file handles marked for deletion");
+	let silent = args[2].clone();
 
-	for file_handle in &file_handles {
-		util::retry(
-			"closing a file handle",
-			|_| -> Result<(), Box<dyn error::Error>> { file_handle.close() },
-			None,
-		)?;
+	if silent != "true" && silent != "false" {
+		return Err(ArgumentError(format!(
+			"Silent needs to be true or false. Instead got: {}",
+			silent
+		))
+		.into());
 	}
 
-	info!(log, "All files deleted");
-
-	for dir in top_directories {
-		let msg = format!("Deleting a directory: {:?}", dir);
-		util::retry(
-			&msg,
-			|attempt| -> Result<(), Box<dyn error::Error>> {
-				if !dir.exists() {
-					return Ok(());
-				}
-
-				info!(
-					log,
-					"Delete directory recursively: {:?} (attempt {})", dir, attempt
-				);
-
-				fs::remove_dir_all(&dir)?;
-				Ok(())
-			},
-			None,
-		)?;
-	}
+	let label = args[3].clone();
 
+	inno_updater::run_update_with_options(log, &code_path, "_", silent == "true", label, options)?;
 	Ok(())
 }
 
-fn move_update(
-	log: &slog::Logger,
-	uninstdat_path: &Path,
-	update_folder_name: &str,
-) -> Result<(), Box<dyn error::Error>> {
-	info!(
-		log,
-		"move_update: {:?}, {}", uninstdat_path, update_folder_name
-	);
-
-	let root_path = uninstdat_path.parent().ok_or_else(|| {
-		io::Error::new(
-			io::ErrorKind::Other,
-			"Could not get parent path of uninstdat",
-		)
-	})?;
+/**
+ * Tells the user the update failed. Silent updates have nobody to tell,
+ * so the error only goes to the log. With `details`, the error is shown
+ * in a dialog which can open the log.
+ */
+fn handle_error(log_path: &str, silent: bool, details: Option<String>) {
+	if silent {
+		return;
+	}
 
-	let mut update_path = PathBuf::from(root_path);
-	update_path.push(update_folder_name);
+	if let Some(details) = details {
+		let msg = t("update-failed-details").replace("{err}", &details);
+		gui::failure_dialog(&msg, log_path);
+		return;
+	}
 
-	let stat = fs::metadata(&update_path)?;
+	let msg = t("update-failed").replace("{log_path}", log_path);
 
-	if !stat.is_dir() {
-		return Err(
-			io::Error::new(io::ErrorKind::Other, "Update folder is not a directory").into(),
-		);
-	}
+	gui::message_box(&msg, &t("product"), gui::MessageBoxType::Error);
+}
 
-	// safely delete all current files
-	delete_existing_version(log, root_path, update_folder_name)?;
+/**
+ * Deletes the log files of previous runs, found in `dir`, which were last
+ * written to more than `max_age` ago.
+ */
+fn prune_logs(log: &slog::Logger, dir: &Path, max_age: Duration) -> Result<(), io::Error> {
+	let now = SystemTime::now();
 
-	// move update to current
-	for entry in fs::read_dir(&update_path)? {
+	for entry in fs::read_dir(dir)? {
 		let entry = entry?;
 		let entry_name = entry.file_name();
-		let entry_name = entry_name
-			.to_str()
-			.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Could not get entry name"))?;
-
-		let mut target = PathBuf::from(root_path);
-		target.push(entry_name);
-
-		let msg = format!("Renaming: {:?}", entry_name);
-		util::retry(
-			&msg,
-			|attempt| {
-				info!(log, "Rename: {:?} (attempt {})", entry_name, attempt);
-				fs::rename(entry.path(), &target)?;
-				Ok(())
-			},
-			None,
-		)?;
-	}
+		let entry_name = match entry_name.to_str() {
+			Some(name) => name,
+			None => continue,
+		};
 
-	info!(log, "Delete: {:?}", update_path);
-	fs::remove_dir_all(update_path)?;
+		if !entry_name.starts_with("vscode-inno-updater-") || !entry_name.ends_with(".log") {
+			continue;
+		}
 
-	Ok(())
-}
+		let modified = entry.metadata()?.modified()?;
+		let age = now.duration_since(modified).unwrap_or_default();
 
-fn patch_uninstdat(
-	log: &slog::Logger,
-	uninstdat_path: &PathBuf,
-	update_folder_name: &str,
-) -> Result<(), Box<dyn error::Error>> {
-	let (header, recs) = read_file(uninstdat_path)?;
-
-	info!(log, "header: {:?}", header);
-	info!(log, "num_recs: {:?}", recs.len());
-
-	let root_path = uninstdat_path.parent().ok_or_else(|| {
-		io::Error::new(
-			io::ErrorKind::Other,
-			"Could not get parent path of uninstdat",
-		)
-	})?;
-
-	let mut update_path = PathBuf::from(root_path);
-	update_path.push(&update_folder_name);
-
-	let recs: Result<Vec<FileRec>, _> = recs
-		.iter()
-		.map(|rec| match rec.typ {
-			model::UninstallRecTyp::DeleteDirOrFiles | model::UninstallRecTyp::DeleteFile => {
-				rec.rebase(&update_path)
-			}
-			_ => Ok(rec.clone()),
-		})
-		.collect();
+		if age <= max_age {
+			continue;
+		}
 
-	info!(log, "Updating uninstall file {:?}", uninstdat_path);
-	write_file(uninstdat_path, &header, recs?)?;
+		match fs::remove_file(entry.path()) {
+			Ok(_) => info!(log, "Pruned old log file: {:?}", entry.path()),
+			Err(err) => warn!(log, "Could not prune log file {:?}: {}", entry.path(), err),
+		}
+	}
 
 	Ok(())
 }
 
-fn do_update(
-	log: &slog::Logger,
-	code_path: &PathBuf,
-	update_folder_name: &str,
-) -> Result<(), Box<dyn error::Error>> {
-	info!(log, "do_update: {:?}, {}", code_path, update_folder_name);
-
-	let root_path = code_path.parent().ok_or_else(|| {
-		io::Error::new(
-			io::ErrorKind::Other,
-			"Could not get parent path of uninstdat",
-		)
-	})?;
-
-	let mut uninstdat_path = PathBuf::from(root_path);
-	uninstdat_path.push("unins000.dat");
+#[derive(Default)]
+struct LogOptions {
+	// where to write the log, if not a new file in the temp folder
+	path: Option<PathBuf>,
+	json: bool,
+	// delete the logs of previous runs older than this
+	prune_after: Option<Duration>,
+	// on failure, show the error with a button opening the log, rather
+	// than a message box naming it
+	failure_dialog: bool,
+}
 
-	move_update(log, &uninstdat_path, update_folder_name)?;
+/**
+ * A logger writing to `writer` in the `--log-format` of `log_options`.
+ */
+fn logger<W: io::Write + Send + 'static>(writer: W, log_options: &LogOptions) -> slog::Logger {
+	if log_options.json {
+		let drain = jsonlog::JsonDrain::new(writer).fuse();
+		let drain = slog_async::Async::new(drain).build().fuse();
+		slog::Logger::root(drain, o!())
+	} else {
+		let decorator = slog_term::PlainDecorator::new(writer);
+		let drain = slog_term::FullFormat::new(decorator).build().fuse();
+		let drain = slog_async::Async::new(drain).build().fuse();
+		slog::Logger::root(drain, o!())
+	}
+}
 
-	// if, for any reason, the uninstdat file is corrupt, let's continue silently
-	// https://github.com/Microsoft/vscode/issues/45607
-	patch_uninstdat(log, &uninstdat_path, update_folder_name).unwrap_or_else(|err| {
-		warn!(log, "Failed to patch uninst.dat file");
-		warn!(log, "{}", err);
+fn __main(args: &[String], options: &UpdateOptions, log_options: &LogOptions) -> ExitCode {
+	let log_path = log_options.path.clone().unwrap_or_else(|| {
+		let mut log_path = env::temp_dir();
+		log_path.push(format!(
+			"vscode-inno-updater-{:?}.log",
+			SystemTime::now()
+				.duration_since(SystemTime::UNIX_EPOCH)
+				.unwrap()
+				.as_secs()
+		));
+		log_path
 	});
 
-	Ok(())
-}
+	// fail before doing anything if the log can't be written
+	let file = match fs::OpenOptions::new()
+		.create(true)
+		.write(true)
+		.truncate(true)
+		.open(&log_path)
+	{
+		Ok(file) => file,
+		Err(err) => {
+			eprintln!("Could not open log file {:?}: {}", log_path, err);
+			return ExitCode::Failure;
+		}
+	};
 
-fn update(
-	log: &slog::Logger,
-	code_path: &PathBuf,
-	update_folder_name: &str,
-	silent: bool,
-	label: String,
-) -> Result<(), Box<dyn error::Error>> {
-	process::wait_or_kill(log, code_path)?;
+	let log = logger(file, log_options);
 
-	info!(log, "Inno Updater v{}", VERSION);
-	info!(log, "Starting update, silent = {}", silent);
+	if let Some(max_age) = log_options.prune_after {
+		prune_logs(&log, &env::temp_dir(), max_age).unwrap_or_else(|err| {
+			warn!(log, "Failed to prune old log files: {}", err);
+		});
+	}
 
-	let (tx, rx) = mpsc::channel();
+	match _main(&log, args, options) {
+		Ok(_) => {
+			info!(log, "Update was successful!");
+			ExitCode::Success
+		}
+		Err(err) => {
+			error!(log, "{}", err);
+			handle_error(
+				&log_path.to_string_lossy(),
+				args[2] == "true",
+				if log_options.failure_dialog {
+					Some(err.to_string())
+				} else {
+					None
+				},
+			);
+
+			ExitCode::from_error(err.as_ref())
+		}
+	}
+}
 
-	thread::spawn(move || {
-		gui::run_progress_window(silent, tx, label);
-	});
+fn parse_options(args: &[String]) -> Result<ParseOptions, ArgumentError> {
+	let mut options = ParseOptions::default();
+	let mut args = args.iter();
+
+	while let Some(arg) = args.next() {
+		match arg.as_ref() {
+			"--validate-utf16" => options.validate_utf16 = true,
+			"--offsets" => options.offsets = true,
+			"--check-size" => options.check_size = true,
+			"--json" => options.json = true,
+			"--decode" => options.decode = true,
+			"--flags" => options.flags = true,
+			"--prometheus" => options.prometheus = true,
+			"--check-encoding" => options.check_encoding = true,
+			"--strings" => options.strings = true,
+			"--expect-arch" => match args.next().map(|a| a.as_ref()) {
+				Some(arch @ "x86") | Some(arch @ "x64") => {
+					options.expect_arch = Some(String::from(arch))
+				}
+				_ => {
+					return Err(ArgumentError(String::from(
+						"--expect-arch needs to be x86 or x64",
+					)))
+				}
+			},
+			"--grep" => match args.next() {
+				Some(grep) => options.grep = Some(grep.clone()),
+				None => return Err(ArgumentError(String::from("--grep needs a value"))),
+			},
+			"--manifest" => match args.next() {
+				Some(root) => options.manifest = Some(PathBuf::from(root)),
+				None => return Err(ArgumentError(String::from("--manifest needs a root"))),
+			},
+			"--relative-to" => match args.next() {
+				Some(root) => options.relative_to = Some(root.clone()),
+				None => return Err(ArgumentError(String::from("--relative-to needs a root"))),
+			},
+			"--check-delete" => match args.next() {
+				Some(root) => options.check_delete = Some(PathBuf::from(root)),
+				None => return Err(ArgumentError(String::from("--check-delete needs a root"))),
+			},
+			_ => return Err(ArgumentError(format!("Unknown parse option: {}", arg))),
+		}
+	}
 
-	let window = rx
-		.recv()
-		.map_err(|_| io::Error::new(io::ErrorKind::Other, "Could not receive GUI window handle"))?;
+	Ok(options)
+}
 
-	do_update(log, code_path, update_folder_name)?;
-	window.exit();
+/**
+ * Removes `flag` and the value following it from `args`, so the value
+ * isn't mistaken for a positional argument, and returns that value.
+ */
+fn take_value(args: &mut Vec<String>, flag: &str) -> Result<Option<String>, ArgumentError> {
+	let index = match args.iter().position(|a| a == flag) {
+		Some(index) => index,
+		None => return Ok(None),
+	};
+
+	if index + 1 >= args.len() {
+		return Err(ArgumentError(format!("{} needs a value", flag)));
+	}
 
-	Ok(())
+	let value = args.remove(index + 1);
+	args.remove(index);
+	Ok(Some(value))
 }
 
-#[derive(Debug, Clone)]
-struct ArgumentError(String);
-
-impl fmt::Display for ArgumentError {
-	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		write!(f, "Bad arguments: {}", self.0)
+/**
+ * Same as `take_value`, for flags whose value is a number.
+ */
+fn take_number(args: &mut Vec<String>, flag: &str) -> Result<Option<u64>, ArgumentError> {
+	match take_value(args, flag)? {
+		Some(value) => value
+			.parse()
+			.map(Some)
+			.map_err(|_| ArgumentError(format!("Invalid {} value: {}", flag, value))),
+		None => Ok(None),
 	}
 }
 
-impl error::Error for ArgumentError {
-	fn description(&self) -> &str {
-		"ArgumentError"
-	}
+/**
+ * Builds the logging options for an update invocation.
+ */
+fn log_options(args: &mut Vec<String>) -> Result<LogOptions, ArgumentError> {
+	let mut options = LogOptions {
+		path: take_value(args, "--log")?.map(PathBuf::from),
+		failure_dialog: args.iter().any(|a| a == "--failure-dialog"),
+		..Default::default()
+	};
+
+	options.json = match take_value(args, "--log-format")? {
+		None => false,
+		Some(ref format) if format == "text" => false,
+		Some(ref format) if format == "json" => true,
+		Some(format) => {
+			return Err(ArgumentError(format!(
+				"Invalid --log-format value: {}",
+				format
+			)))
+		}
+	};
 
-	fn cause(&self) -> Option<&dyn error::Error> {
-		None
+	if let Some(days) = take_number(args, "--prune-logs")? {
+		options.prune_after = Some(Duration::from_secs(days * 24 * 60 * 60));
 	}
-}
 
-fn _main(log: &slog::Logger, args: &[String]) -> Result<(), Box<dyn error::Error>> {
-	info!(log, "Starting: {}, {}, {}", args[1], args[2], args[3]);
+	Ok(options)
+}
 
-	let code_path = PathBuf::from(&args[1]);
+/**
+ * Builds the options for an update invocation.
+ */
+fn update_options(args: &mut Vec<String>) -> Result<UpdateOptions, ArgumentError> {
+	let mut options = UpdateOptions {
+		wait: process::WaitOptions {
+			strict: args.iter().any(|a| a == "--strict-wait"),
+			kill_tree: args.iter().any(|a| a == "--kill-tree"),
+			..Default::default()
+		},
+		dry_run: args.iter().any(|a| a == "--dry-run"),
+		..Default::default()
+	};
+
+	if let Some(secs) = take_number(args, "--wait-timeout")? {
+		options.wait.timeout = Duration::from_secs(secs);
+	}
 
-	if !code_path.is_absolute() {
-		return Err(ArgumentError(format!(
-			"Code path needs to be absolute. Instead got: {}",
-			args[1]
-		))
-		.into());
+	// 0 terminates processes without asking their windows to close first
+	if let Some(millis) = take_number(args, "--close-grace")? {
+		options.wait.close_grace = Duration::from_millis(millis);
 	}
 
-	if !code_path.exists() {
-		return Err(ArgumentError(format!("Code path doesn't seem to exist: {}", args[1])).into());
+	if let Some(attempts) = take_number(args, "--kill-attempts")? {
+		if attempts == 0 || attempts > u32::MAX as u64 {
+			return Err(ArgumentError(format!(
+				"Invalid --kill-attempts value: {}",
+				attempts
+			)));
+		}
+
+		options.wait.kill_attempts = attempts as u32;
 	}
 
-	let silent = args[2].clone();
+	if let Some(millis) = take_number(args, "--delete-grace")? {
+		options.delete_grace = Duration::from_millis(millis);
+	}
 
-	if silent != "true" && silent != "false" {
-		return Err(ArgumentError(format!(
-			"Silent needs to be true or false. Instead got: {}",
-			silent
-		))
-		.into());
+	if let Some(secs) = take_number(args, "--delete-timeout")? {
+		options.delete_timeout = Some(Duration::from_secs(secs));
 	}
 
-	let label = args[3].clone();
+	if let Some(name) = take_value(args, "--uninst-name")? {
+		// a bare file name, to be found next to the code path
+		if name.is_empty() || name == "." || name == ".." || name.contains(&['/', '\\', ':'][..]) {
+			return Err(ArgumentError(format!(
+				"Invalid --uninst-name value: {}",
+				name
+			)));
+		}
 
-	update(log, &code_path, "_", silent == "true", label)
-}
+		options.uninst_name = Some(name);
+	}
 
-fn handle_error(log_path: &str) {
-	let msg = format!(
-		"Failed to install Visual Studio Code update.\n\n\
-		Updates may fail due to anti-virus software and/or runaway processes. Please try restarting your machine before attempting to update again.\n\n\
-		Please read the log file for more information:\n\n\
-		{log_path}"
-	);
+	options.expected_app_id = take_value(args, "--expect-app-id")?;
 
-	gui::message_box(&msg, "Visual Studio Code", gui::MessageBoxType::Error);
+	Ok(options)
 }
 
-fn __main(args: &[String]) -> i32 {
-	let mut log_path = env::temp_dir();
-	log_path.push(format!(
+fn main() {
+	let args: Vec<String> = env::args().collect();
+	let log_path = format!(
 		"vscode-inno-updater-{:?}.log",
 		SystemTime::now()
 			.duration_since(SystemTime::UNIX_EPOCH)
 			.unwrap()
 			.as_secs()
-	));
+	);
 
-	let file = fs::OpenOptions::new()
-		.create(true)
-		.write(true)
-		.truncate(true)
-		.open(&log_path)
-		.unwrap();
+	if args.len() >= 3 && args[1] == "--parse" {
+		let path = PathBuf::from(&args[2]);
+		let options = parse_options(&args[3..]).unwrap_or_else(|err| {
+			eprintln!("{}", err);
+			ExitCode::BadArguments.exit();
+		});
 
-	let decorator = slog_term::PlainDecorator::new(file);
-	let drain = slog_term::FullFormat::new(decorator).build().fuse();
-	let drain = slog_async::Async::new(drain).build().fuse();
-	let log = slog::Logger::root(drain, o!());
+		inno_updater::parse(&path, &options).unwrap_or_else(|err| {
+			eprintln!("{}", err);
+			ExitCode::from_error(err.as_ref()).exit();
+		});
+	} else if args.len() == 3 && args[1] == "--verify" {
+		let path = PathBuf::from(&args[2]);
 
-	match _main(&log, args) {
-		Ok(_) => {
-			info!(log, "Update was successful!");
-			0
-		}
-		Err(err) => {
-			error!(log, "{}", err);
-			handle_error(log_path.to_str().unwrap());
-			1
+		let result = inno_updater::verify(&path);
+
+		match result {
+			Ok(ref report) if report.warnings.is_empty() => {
+				println!("OK, {} records", report.num_recs)
+			}
+			Ok(ref report) => {
+				for warning in &report.warnings {
+					println!("Warning: {}", warning);
+				}
+			}
+			Err(ref err) => eprintln!("{}", err),
 		}
-	}
-}
 
-fn parse(path: &Path) -> Result<(), Box<dyn error::Error>> {
-	let (header, recs) = read_file(path)?;
+		ExitCode::from_verify(&result).exit();
+	} else if args.len() == 4 && args[1] == "--repair-crc" {
+		let input = PathBuf::from(&args[2]);
+		let output = PathBuf::from(&args[3]);
 
-	println!("{:?}", header);
+		eprintln!(
+			"Warning: CRCs are not checked while repairing, so real corruption in {:?} will go unnoticed",
+			input
+		);
 
-	use std::collections::HashMap;
-	let mut map: HashMap<u16, u32> = HashMap::new();
+		match inno_updater::repair_crc(&input, &output) {
+			Ok(num_recs) => println!("Wrote {} records to {:?}", num_recs, output),
+			Err(err) => {
+				eprintln!("{}", err);
+				ExitCode::from_error(err.as_ref()).exit();
+			}
+		}
+	} else if (args.len() == 4 || (args.len() == 5 && args[4] == "--verbose"))
+		&& args[1] == "--diff"
+	{
+		let old = PathBuf::from(&args[2]);
+		let new = PathBuf::from(&args[3]);
 
-	for rec in recs {
-		let count = map.entry(rec.typ as u16).or_insert(0);
-		*count += 1;
-	}
+		inno_updater::diff(&old, &new, args.len() == 5).unwrap_or_else(|err| {
+			eprintln!("{}", err);
+			ExitCode::from_error(err.as_ref()).exit();
+		});
+	} else if args.len() == 3 && args[1] == "--repair-header" {
+		let path = PathBuf::from(&args[2]);
 
-	for (k, c) in &map {
-		println!("Records 0x{:x} {}", k, c);
-	}
+		match inno_updater::repair_header(&path) {
+			Ok(true) => println!("Repaired header CRC of {:?}", path),
+			Ok(false) => println!("Header CRC of {:?} is already correct", path),
+			Err(err) => {
+				eprintln!("{}", err);
+				ExitCode::from_error(err.as_ref()).exit();
+			}
+		}
+	} else if args.len() == 3 && args[1] == "--dump" {
+		let path = PathBuf::from(&args[2]);
 
-	Ok(())
-}
+		inno_updater::dump(&path).unwrap_or_else(|err| {
+			eprintln!("{}", err);
+			ExitCode::from_error(err.as_ref()).exit();
+		});
+	} else if args.len() == 3 && args[1] == "--check-locks" {
+		let code_path = PathBuf::from(&args[2]);
 
-fn main() {
-	let args: Vec<String> = env::args().collect();
-	let log_path = format!(
-		"vscode-inno-updater-{:?}.log",
-		SystemTime::now()
-			.duration_since(SystemTime::UNIX_EPOCH)
-			.unwrap()
-			.as_secs()
-	);
+		inno_updater::check_locks(&code_path).unwrap_or_else(|err| {
+			eprintln!("{}", err);
+			ExitCode::Failure.exit();
+		});
+	} else if (args.len() == 2 || args.len() == 3) && args[1] == "--list-processes" {
+		let filter = args.get(2).map(|filter| filter.as_str());
 
-	if args.len() == 3 && args[1] == "--parse" {
-		let path = PathBuf::from(&args[2]);
-		parse(&path).unwrap_or_else(|err| {
+		inno_updater::list_processes(filter).unwrap_or_else(|err| {
 			eprintln!("{}", err);
-			std::process::exit(1);
+			ExitCode::Failure.exit();
 		});
+	} else if args.len() >= 3 && args[1] == "--wait-only" {
+		let mut args = args;
+		let log_options = log_options(&mut args).unwrap_or_else(|err| {
+			eprintln!("Error: {}", err);
+			ExitCode::BadArguments.exit();
+		});
+
+		if args.len() > 4 {
+			eprintln!("Error: Bad usage");
+			ExitCode::BadArguments.exit();
+		}
+
+		let code_path = PathBuf::from(&args[2]);
+		let mut options = process::WaitOptions::default();
+
+		if let Some(secs) = args.get(3) {
+			let secs = secs.parse::<u64>().unwrap_or_else(|_| {
+				eprintln!("Invalid --wait-only timeout: {}", secs);
+				ExitCode::BadArguments.exit();
+			});
+
+			options.timeout = Duration::from_secs(secs);
+		}
+
+		// logs to stderr, unless given a --log file
+		let log = match log_options.path {
+			Some(ref path) => match fs::File::create(path) {
+				Ok(file) => logger(file, &log_options),
+				Err(err) => {
+					eprintln!("Could not open log file {:?}: {}", path, err);
+					ExitCode::Failure.exit();
+				}
+			},
+			None => logger(io::stderr(), &log_options),
+		};
+
+		let code = match process::wait_until_exited(&log, &code_path, &options) {
+			Ok(true) => ExitCode::Success,
+			Ok(false) => ExitCode::TimedOut,
+			Err(err) => {
+				eprintln!("{}", err);
+				ExitCode::Failure
+			}
+		};
+
+		// flushes the log before exiting
+		drop(log);
+		code.exit();
 	} else if args.len() == 3 && args[1] == "--gui" {
 		let (tx, rx) = mpsc::channel();
 		let label = args[2].clone();
@@ -528,27 +625,257 @@ fn main() {
 					"[[Simulated error message]]",
 				)))
 			},
-			Some(5),
+			&util::RetryOptions {
+				max_attempts: 5,
+				..Default::default()
+			},
 		);
 
 		if result.is_err() {
-			handle_error(&log_path);
+			handle_error(&log_path, false, None);
 		}
 
 		window.exit();
 	} else if args.len() == 3 && args[1] == "--error" {
-		handle_error(&log_path);
+		handle_error(&log_path, false, None);
 	} else if args.len() == 2 && (args[1] == "--version" || args[1] == "-v") {
 		eprintln!("Inno Update v{}", VERSION);
 	} else {
+		let mut args = args;
+		let parsed = log_options(&mut args)
+			.and_then(|log_options| Ok((update_options(&mut args)?, log_options)));
+
+		let (options, log_options) = parsed.unwrap_or_else(|err| {
+			eprintln!("Inno Update v{}", VERSION);
+			eprintln!("Error: {}", err);
+			ExitCode::BadArguments.exit();
+		});
+
 		let args: Vec<String> = args.into_iter().filter(|a| !a.starts_with("--")).collect();
 
 		if args.len() < 4 {
 			eprintln!("Inno Update v{}", VERSION);
 			eprintln!("Error: Bad usage");
-			std::process::exit(1);
+			ExitCode::BadArguments.exit();
 		} else {
-			std::process::exit(__main(&args));
+			__main(&args, &options, &log_options).exit();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn run(args: &[&str]) -> ExitCode {
+		let log = slog::Logger::root(slog::Discard, o!());
+		let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+
+		match _main(&log, &args, &UpdateOptions::default()) {
+			Ok(()) => ExitCode::Success,
+			Err(err) => ExitCode::from_error(err.as_ref()),
+		}
+	}
+
+	#[test]
+	fn main_errors_map_to_exit_codes() {
+		assert_eq!(
+			run(&["inno_updater", "Code.exe", "false", "_"]),
+			ExitCode::BadArguments
+		);
+		assert_eq!(
+			run(&["inno_updater", "C:\\missing\\Code.exe", "false", "_"]),
+			ExitCode::BadArguments
+		);
+
+		let io_error = |kind| -> Box<dyn error::Error> { io::Error::new(kind, "failed").into() };
+		let code = |err: UpdateError| ExitCode::from_error(&err);
+
+		assert_eq!(
+			code(UpdateError::BadArguments(io_error(io::ErrorKind::Other))),
+			ExitCode::BadArguments
+		);
+		assert_eq!(
+			code(UpdateError::ProcessKillFailed(io_error(
+				io::ErrorKind::Other
+			))),
+			ExitCode::ProcessKillFailed
+		);
+		assert_eq!(
+			code(UpdateError::ProcessKillFailed(io_error(
+				io::ErrorKind::TimedOut
+			))),
+			ExitCode::TimedOut
+		);
+		assert_eq!(
+			code(UpdateError::DeleteFailed(io_error(io::ErrorKind::Other))),
+			ExitCode::DeleteFailed
+		);
+		assert_eq!(
+			code(UpdateError::DeleteFailed(io_error(io::ErrorKind::TimedOut))),
+			ExitCode::TimedOut
+		);
+		assert_eq!(
+			code(UpdateError::PermissionDenied(io_error(
+				io::ErrorKind::PermissionDenied
+			))),
+			ExitCode::PermissionDenied
+		);
+		assert_eq!(
+			code(UpdateError::Other(io_error(io::ErrorKind::Other))),
+			ExitCode::UpdateFailed
+		);
+		assert_eq!(
+			ExitCode::from_error(io_error(io::ErrorKind::InvalidData).as_ref()),
+			ExitCode::ParseError
+		);
+		assert_eq!(
+			ExitCode::from_error(io_error(io::ErrorKind::NotFound).as_ref()),
+			ExitCode::Failure
+		);
+	}
+
+	#[test]
+	fn corrupt_logs_exit_with_parse_errors() {
+		let path = env::temp_dir().join(format!("inno-updater-corrupt-{}.dat", std::process::id()));
+		fs::write(&path, b"not an uninstall log").unwrap();
+
+		let parsed = inno_updater::parse(&path, &ParseOptions::default());
+		let dumped = inno_updater::dump(&path);
+		let verified = inno_updater::verify(&path);
+		fs::remove_file(&path).unwrap();
+
+		assert_eq!(
+			ExitCode::from_error(parsed.unwrap_err().as_ref()),
+			ExitCode::ParseError
+		);
+		assert_eq!(
+			ExitCode::from_error(dumped.unwrap_err().as_ref()),
+			ExitCode::ParseError
+		);
+		assert_eq!(ExitCode::from_verify(&verified), ExitCode::ParseError);
+	}
+
+	#[test]
+	fn verify_results_map_to_exit_codes() {
+		let report = |warnings: Vec<String>| -> Result<VerifyReport, Box<dyn error::Error>> {
+			Ok(VerifyReport {
+				num_recs: 1,
+				warnings,
+			})
+		};
+
+		assert_eq!(ExitCode::from_verify(&report(vec![])), ExitCode::Success);
+		assert_eq!(
+			ExitCode::from_verify(&report(vec![String::from(
+				"Record 0 has unknown type 0x99"
+			)])),
+			ExitCode::VerifyWarnings
+		);
+		assert_eq!(
+			ExitCode::from_verify(&Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"Block header crc32 check failed"
+			)
+			.into())),
+			ExitCode::ParseError
+		);
+		assert_eq!(
+			ExitCode::from_verify(&Err(io::Error::from(io::ErrorKind::NotFound).into())),
+			ExitCode::Failure
+		);
+	}
+
+	#[test]
+	fn close_grace_can_be_turned_off() {
+		let parse = |args: &[&str]| {
+			let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+			update_options(&mut args).unwrap().wait.close_grace
+		};
+
+		assert_eq!(parse(&[]), Duration::from_secs(5));
+		assert_eq!(parse(&["--close-grace", "0"]), Duration::from_secs(0));
+		assert_eq!(
+			parse(&["--close-grace", "1500"]),
+			Duration::from_millis(1500)
+		);
+	}
+
+	#[test]
+	fn kill_attempts_are_capped_by_the_option() {
+		let parse = |args: &[&str]| {
+			let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+			update_options(&mut args).map(|options| options.wait.kill_attempts)
+		};
+
+		assert_eq!(parse(&[]).unwrap(), 5);
+		assert_eq!(parse(&["--kill-attempts", "2"]).unwrap(), 2);
+		assert!(parse(&["--kill-attempts", "0"]).is_err());
+		assert!(parse(&["--kill-attempts", "4294967296"]).is_err());
+	}
+
+	#[test]
+	fn loggers_follow_the_log_format() {
+		let written = |args: &[&str]| {
+			let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+			let log_options = log_options(&mut args).unwrap();
+			let path = log_options.path.clone().unwrap();
+
+			let log = logger(fs::File::create(&path).unwrap(), &log_options);
+			info!(log, "Waiting for processes");
+			drop(log);
+
+			let contents = fs::read_to_string(&path).unwrap();
+			fs::remove_file(&path).unwrap();
+			contents
+		};
+
+		let path = env::temp_dir().join(format!(
+			"inno-updater-log-format-{}.log",
+			std::process::id()
+		));
+		let path = path.to_str().unwrap();
+
+		let json = written(&["--log", path, "--log-format", "json"]);
+		assert!(json.starts_with("{\"ts\": "), "{}", json);
+		assert!(
+			json.contains("\"msg\": \"Waiting for processes\""),
+			"{}",
+			json
+		);
+
+		let text = written(&["--log", path]);
+		assert!(!text.starts_with('{'), "{}", text);
+		assert!(text.contains("Waiting for processes"), "{}", text);
+	}
+
+	#[test]
+	fn prune_logs_only_deletes_old_logs() {
+		let log = slog::Logger::root(slog::Discard, o!());
+		let dir = env::temp_dir().join(format!("inno-updater-prune-logs-{}", std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+
+		let day = Duration::from_secs(24 * 60 * 60);
+		let files = [
+			("vscode-inno-updater-1.log", 10 * day),
+			("vscode-inno-updater-2.log", day),
+			("vscode-inno-updater-3.txt", 10 * day),
+			("other-1.log", 10 * day),
+		];
+
+		for &(name, age) in &files {
+			let file = fs::File::create(dir.join(name)).unwrap();
+			file.set_modified(SystemTime::now() - age).unwrap();
 		}
+
+		prune_logs(&log, &dir, 7 * day).unwrap();
+
+		assert!(!dir.join("vscode-inno-updater-1.log").exists());
+		assert!(dir.join("vscode-inno-updater-2.log").exists());
+		assert!(dir.join("vscode-inno-updater-3.txt").exists());
+		assert!(dir.join("other-1.log").exists());
+
+		fs::remove_dir_all(&dir).unwrap();
 	}
 }
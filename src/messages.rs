@@ -0,0 +1,117 @@
+/*-----------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *----------------------------------------------------------------------------------------*/
+
+// the keys every catalog may translate; a missing key falls back to English
+const EN: &[(&str, &str)] = &[
+	("product", "Visual Studio Code"),
+	("updating", "Visual Studio Code is updating..."),
+	(
+		"update-failed",
+		"Failed to install Visual Studio Code update.\n\n\
+		Updates may fail due to anti-virus software and/or runaway processes. Please try restarting your machine before attempting to update again.\n\n\
+		Please read the log file for more information:\n\n\
+		{log_path}",
+	),
+	(
+		"update-failed-details",
+		"Failed to install Visual Studio Code update:\n\n{err}\n\n\
+		Updates may fail due to anti-virus software and/or runaway processes. Please try restarting your machine before attempting to update again.",
+	),
+	("collected-files", "Found {count} files"),
+	("deleted-files", "Deleted {done} of {total} files"),
+	("open-log", "Open log"),
+	("close", "Close"),
+	(
+		"retry-failed",
+		"There was an error while {task}:\n\n{err}\n\n\
+		Please verify there are no Visual Studio Code processes still executing.",
+	),
+];
+
+const DE: &[(&str, &str)] = &[
+	("updating", "Visual Studio Code wird aktualisiert..."),
+	(
+		"update-failed",
+		"Das Update für Visual Studio Code konnte nicht installiert werden.\n\n\
+		Updates können aufgrund von Antivirensoftware und/oder hängenden Prozessen fehlschlagen. Starten Sie den Computer neu, bevor Sie das Update erneut versuchen.\n\n\
+		Weitere Informationen finden Sie in der Protokolldatei:\n\n\
+		{log_path}",
+	),
+	(
+		"update-failed-details",
+		"Das Update für Visual Studio Code konnte nicht installiert werden:\n\n{err}\n\n\
+		Updates können aufgrund von Antivirensoftware und/oder hängenden Prozessen fehlschlagen. Starten Sie den Computer neu, bevor Sie das Update erneut versuchen.",
+	),
+	("collected-files", "{count} Dateien gefunden"),
+	("deleted-files", "{done} von {total} Dateien gelöscht"),
+	("open-log", "Protokoll öffnen"),
+	("close", "Schließen"),
+	(
+		"retry-failed",
+		"Fehler bei folgendem Vorgang: {task}\n\n{err}\n\n\
+		Stellen Sie sicher, dass keine Visual Studio Code-Prozesse mehr ausgeführt werden.",
+	),
+];
+
+const CATALOGS: &[(&str, &[(&str, &str)])] = &[("en", EN), ("de", DE)];
+
+/**
+ * The locale code of the user's UI language, as used to key `CATALOGS`.
+ */
+fn locale() -> &'static str {
+	use windows_sys::Win32::Globalization::GetUserDefaultUILanguage;
+
+	// the low 10 bits of a LANGID are its primary language
+	match unsafe { GetUserDefaultUILanguage() } & 0x3ff {
+		0x07 => "de",
+		_ => "en",
+	}
+}
+
+fn lookup(catalog: &[(&str, &'static str)], key: &str) -> Option<&'static str> {
+	catalog.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+}
+
+/**
+ * The string for `key` in the user's UI language, falling back to English.
+ * Placeholders such as `{log_path}` are left for the caller to fill in.
+ */
+pub fn t(key: &str) -> String {
+	translate(locale(), key).to_string()
+}
+
+fn translate<'a>(locale: &str, key: &'a str) -> &'a str {
+	CATALOGS
+		.iter()
+		.find(|(code, _)| *code == locale)
+		.and_then(|(_, catalog)| lookup(catalog, key))
+		.or_else(|| lookup(EN, key))
+		.unwrap_or(key)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn missing_translations_fall_back_to_english() {
+		assert_eq!(translate("de", "close"), "Schließen");
+
+		// a key German doesn't translate, a locale without a catalog, and a
+		// key nobody knows
+		assert_eq!(translate("de", "product"), "Visual Studio Code");
+		assert_eq!(translate("fr", "close"), "Close");
+		assert_eq!(translate("de", "no-such-key"), "no-such-key");
+	}
+
+	#[test]
+	fn catalogs_only_translate_english_keys() {
+		for &(code, catalog) in CATALOGS {
+			for &(key, _) in catalog {
+				assert!(lookup(EN, key).is_some(), "{}: {}", code, key);
+			}
+		}
+	}
+}
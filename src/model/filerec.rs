@@ -8,26 +8,29 @@ use std::io::prelude::*;
 use std::path::Path;
 use std::string::String;
 use std::{error, fmt};
+use strings::{decode_windows_1252, encode_windows_1252, strip_path_prefix, to_json_string};
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub enum UninstallRecTyp {
-	UserDefined = 0x01,
-	StartInstall = 0x10,
-	EndInstall = 0x11,
-	CompiledCode = 0x20,
-	Run = 0x80,
-	DeleteDirOrFiles = 0x81,
-	DeleteFile = 0x82,
-	DeleteGroupOrItem = 0x83,
-	IniDeleteEntry = 0x84,
-	IniDeleteSection = 0x85,
-	RegDeleteEntireKey = 0x86,
-	RegClearValue = 0x87,
-	RegDeleteKeyIfEmpty = 0x88,
-	RegDeleteValue = 0x89,
-	DecrementSharedCount = 0x8A,
-	RefreshFileAssoc = 0x8B,
-	MutexCheck = 0x8C,
+	UserDefined,
+	StartInstall,
+	EndInstall,
+	CompiledCode,
+	Run,
+	DeleteDirOrFiles,
+	DeleteFile,
+	DeleteGroupOrItem,
+	IniDeleteEntry,
+	IniDeleteSection,
+	RegDeleteEntireKey,
+	RegClearValue,
+	RegDeleteKeyIfEmpty,
+	RegDeleteValue,
+	DecrementSharedCount,
+	RefreshFileAssoc,
+	MutexCheck,
+	// a record type we don't know about, kept as is so it can be written back
+	Unknown(u16),
 }
 
 impl UninstallRecTyp {
@@ -50,12 +53,113 @@ impl UninstallRecTyp {
 			0x8A => UninstallRecTyp::DecrementSharedCount,
 			0x8B => UninstallRecTyp::RefreshFileAssoc,
 			0x8C => UninstallRecTyp::MutexCheck,
-			_ => panic!(""),
+			_ => UninstallRecTyp::Unknown(i),
 		}
 	}
+
+	pub fn value(&self) -> u16 {
+		match *self {
+			UninstallRecTyp::UserDefined => 0x01,
+			UninstallRecTyp::StartInstall => 0x10,
+			UninstallRecTyp::EndInstall => 0x11,
+			UninstallRecTyp::CompiledCode => 0x20,
+			UninstallRecTyp::Run => 0x80,
+			UninstallRecTyp::DeleteDirOrFiles => 0x81,
+			UninstallRecTyp::DeleteFile => 0x82,
+			UninstallRecTyp::DeleteGroupOrItem => 0x83,
+			UninstallRecTyp::IniDeleteEntry => 0x84,
+			UninstallRecTyp::IniDeleteSection => 0x85,
+			UninstallRecTyp::RegDeleteEntireKey => 0x86,
+			UninstallRecTyp::RegClearValue => 0x87,
+			UninstallRecTyp::RegDeleteKeyIfEmpty => 0x88,
+			UninstallRecTyp::RegDeleteValue => 0x89,
+			UninstallRecTyp::DecrementSharedCount => 0x8A,
+			UninstallRecTyp::RefreshFileAssoc => 0x8B,
+			UninstallRecTyp::MutexCheck => 0x8C,
+			UninstallRecTyp::Unknown(i) => i,
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match *self {
+			UninstallRecTyp::UserDefined => "UserDefined",
+			UninstallRecTyp::StartInstall => "StartInstall",
+			UninstallRecTyp::EndInstall => "EndInstall",
+			UninstallRecTyp::CompiledCode => "CompiledCode",
+			UninstallRecTyp::Run => "Run",
+			UninstallRecTyp::DeleteDirOrFiles => "DeleteDirOrFiles",
+			UninstallRecTyp::DeleteFile => "DeleteFile",
+			UninstallRecTyp::DeleteGroupOrItem => "DeleteGroupOrItem",
+			UninstallRecTyp::IniDeleteEntry => "IniDeleteEntry",
+			UninstallRecTyp::IniDeleteSection => "IniDeleteSection",
+			UninstallRecTyp::RegDeleteEntireKey => "RegDeleteEntireKey",
+			UninstallRecTyp::RegClearValue => "RegClearValue",
+			UninstallRecTyp::RegDeleteKeyIfEmpty => "RegDeleteKeyIfEmpty",
+			UninstallRecTyp::RegDeleteValue => "RegDeleteValue",
+			UninstallRecTyp::DecrementSharedCount => "DecrementSharedCount",
+			UninstallRecTyp::RefreshFileAssoc => "RefreshFileAssoc",
+			UninstallRecTyp::MutexCheck => "MutexCheck",
+			UninstallRecTyp::Unknown(_) => "Unknown",
+		}
+	}
+
+	/**
+	 * Whether records of this type hold paths of the installation, which
+	 * move along with it when an update is applied.
+	 */
+	pub fn is_path_record(&self) -> bool {
+		matches!(
+			*self,
+			UninstallRecTyp::DeleteDirOrFiles | UninstallRecTyp::DeleteFile
+		)
+	}
+
+	/**
+	 * Whether records of this type hold a string table of text. Compiled
+	 * code packs bytecode into one, and unknown types hold anything.
+	 */
+	pub fn has_strings(&self) -> bool {
+		!matches!(
+			*self,
+			UninstallRecTyp::CompiledCode | UninstallRecTyp::Unknown(_)
+		)
+	}
 }
 
-#[derive(Clone)]
+// extra_data flags of DeleteDirOrFiles records
+const DELETE_DIR_OR_FILES_FLAGS: [(u32, &str); 5] = [
+	(0x01, "IsDir"),
+	(0x02, "DeleteFiles"),
+	(0x04, "DeleteSubdirsAlso"),
+	(0x08, "CallChangeNotify"),
+	(0x10, "DisableFsRedir"),
+];
+
+// extra_data flags of DeleteFile records
+const DELETE_FILE_FLAGS: [(u32, &str); 15] = [
+	(0x0001, "ExistedBeforeInstall"),
+	(0x0002, "Extra"),
+	(0x0004, "IsFont"),
+	(0x0008, "SharedFile"),
+	(0x0010, "RegisteredServer"),
+	(0x0020, "CallChangeNotify"),
+	(0x0040, "RegisteredTypeLib"),
+	(0x0080, "RestartDelete"),
+	(0x0100, "RemoveReadOnly"),
+	(0x0200, "NoSharedFilePrompt"),
+	(0x0400, "SharedFileIn64BitRegView"),
+	(0x0800, "DisableFsRedir"),
+	(0x1000, "Unregister64Bit"),
+	(0x2000, "GacInstalled"),
+	(0x4000, "PerUserFont"),
+];
+
+/**
+ * An uninstall record. Equality and hashing are byte-level: two records
+ * whose paths decode to the same strings, but which store them with a
+ * different encoding, are not equal.
+ */
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct FileRec {
 	pub typ: UninstallRecTyp,
 	extra_data: u32,
@@ -67,7 +171,7 @@ impl fmt::Debug for FileRec {
 		write!(
 			formatter,
 			"FileRec 0x{:x} 0x{:x} {} bytes",
-			self.typ as u32,
+			self.typ.value(),
 			self.extra_data as u32,
 			self.data.len(),
 		)
@@ -93,38 +197,53 @@ impl<'a> error::Error for StringDecodeError<'a> {
 	}
 }
 
-fn decode_strings<'a>(data: &[u8]) -> Result<Vec<String>, StringDecodeError<'a>> {
-	let mut result: Vec<String> = Vec::with_capacity(10);
-	let mut slice = data.clone();
+#[derive(Copy, Clone, PartialEq)]
+enum StringEncoding {
+	Ansi,
+	Unicode,
+}
+
+/**
+ * Splits the string table of a file rec into the raw bytes of each string
+ * and their encoding, without decoding them.
+ *
+ * Each string is prefixed by a header byte: 0x00 to 0xfc is the size of a
+ * short ANSI string, 0xfd is followed by a 16 bit ANSI size and 0xfe by a
+ * 32 bit size, which is negative for Unicode strings. 0xff ends the table.
+ */
+fn split_rec_strings<'a>(
+	data: &[u8],
+) -> Result<Vec<(&[u8], StringEncoding)>, StringDecodeError<'a>> {
+	let mut result: Vec<(&[u8], StringEncoding)> = Vec::with_capacity(10);
+	let mut slice = data;
 
 	loop {
-		let reader: &mut dyn Read = &mut slice.clone();
+		let reader: &mut dyn Read = &mut &slice[..];
 		let byte_result = reader
 			.read_u8()
 			.map_err(|_| StringDecodeError("Failed to parse file rec string header"))?;
 
-		match byte_result {
-			0x00..=0xfc => panic!("What 0x{:x}", byte_result),
-			0xfd => panic!("What 0x{:x}", byte_result),
+		let (header_size, size, encoding) = match byte_result {
+			0x00..=0xfc => (1, byte_result as usize, StringEncoding::Ansi),
+			0xfd => {
+				let size = reader
+					.read_u16::<LittleEndian>()
+					.map_err(|_| StringDecodeError("Failed to parse file rec string size"))?;
+
+				(3, size as usize, StringEncoding::Ansi)
+			}
 			0xfe => {
 				let size = reader
 					.read_i32::<LittleEndian>()
 					.map_err(|_| StringDecodeError("Failed to parse file rec string size"))?;
 
-				let size = -size as usize;
-
-				if size > 0 {
-					assert_eq!(size % 2, 0);
-
-					let mut u16data: Vec<u16> = vec![0; size / 2];
-					LittleEndian::read_u16_into(&slice[5..5 + size], &mut u16data);
-
-					let string = String::from_utf16(&u16data)
-						.map_err(|_| StringDecodeError("Failed to parse file rec data string"))?;
-					result.push(string);
-				}
+				let encoding = if size < 0 {
+					StringEncoding::Unicode
+				} else {
+					StringEncoding::Ansi
+				};
 
-				slice = &slice[5 + size..];
+				(5, size.unsigned_abs() as usize, encoding)
 			}
 			0xff => {
 				if slice.len() != 1 {
@@ -132,10 +251,89 @@ fn decode_strings<'a>(data: &[u8]) -> Result<Vec<String>, StringDecodeError<'a>>
 				}
 				return Ok(result);
 			}
+		};
+
+		if slice.len() < header_size + size {
+			return Err(StringDecodeError("File rec string is truncated"));
+		}
+
+		if encoding == StringEncoding::Unicode && size % 2 != 0 {
+			return Err(StringDecodeError("Odd file rec string byte length"));
 		}
+
+		result.push((&slice[header_size..header_size + size], encoding));
+		slice = &slice[header_size + size..];
 	}
 }
 
+fn to_u16s(bytes: &[u8]) -> Vec<u16> {
+	let mut u16data: Vec<u16> = vec![0; bytes.len() / 2];
+	LittleEndian::read_u16_into(bytes, &mut u16data);
+	u16data
+}
+
+/**
+ * Decodes the string table of a file rec, keeping track of each string's
+ * encoding so it can be written back the same way.
+ */
+fn decode_rec_strings<'a>(
+	data: &[u8],
+) -> Result<Vec<(String, StringEncoding)>, StringDecodeError<'a>> {
+	split_rec_strings(data)?
+		.into_iter()
+		.map(|(bytes, encoding)| {
+			let string = match encoding {
+				StringEncoding::Unicode => String::from_utf16(&to_u16s(bytes))
+					.map_err(|_| StringDecodeError("Failed to parse file rec data string"))?,
+				StringEncoding::Ansi => decode_windows_1252(bytes),
+			};
+
+			Ok((string, encoding))
+		})
+		.collect()
+}
+
+fn decode_strings<'a>(data: &[u8]) -> Result<Vec<String>, StringDecodeError<'a>> {
+	Ok(decode_rec_strings(data)?
+		.into_iter()
+		.map(|(string, _)| string)
+		.collect())
+}
+
+/**
+ * Strictly validates the string table of a file rec, without decoding it.
+ *
+ * Every UTF-16 string must have an even byte length and properly paired
+ * surrogates. ANSI strings (short, 0xfd and positive 0xfe sizes) are skipped.
+ */
+fn validate_strings<'a>(data: &[u8]) -> Result<(), StringDecodeError<'a>> {
+	for (bytes, encoding) in split_rec_strings(data)? {
+		if encoding == StringEncoding::Unicode
+			&& std::char::decode_utf16(to_u16s(bytes)).any(|c| c.is_err())
+		{
+			return Err(StringDecodeError("Unpaired surrogate in file rec string"));
+		}
+	}
+
+	Ok(())
+}
+
+/**
+ * Characters which hardly ever show up in genuine paths: control and
+ * replacement characters, and ASCII which ended up in the high byte of a
+ * UTF-16 code unit, as it does when the string's byte order is swapped.
+ */
+fn is_suspicious_char(c: char) -> bool {
+	let value = c as u32;
+
+	c == '\u{fffd}' || c.is_control() || (value & 0xff == 0 && (0x2000..=0x7e00).contains(&value))
+}
+
+fn hex_dump(data: &[u8]) -> String {
+	let bytes: Vec<String> = data.iter().map(|b| format!("{:02x}", b)).collect();
+	bytes.join(" ")
+}
+
 #[derive(Debug, Clone)]
 pub struct StringEncodeError<'a>(&'a str);
 
@@ -155,26 +353,52 @@ impl<'a> error::Error for StringEncodeError<'a> {
 	}
 }
 
-fn encode_strings<'a>(strings: &[String]) -> Result<Vec<u8>, StringEncodeError<'a>> {
+fn encode_rec_strings<'a>(
+	strings: &[(String, StringEncoding)],
+) -> Result<Vec<u8>, StringEncodeError<'a>> {
 	let mut result: Vec<u8> = Vec::with_capacity(1024);
 
-	for string in strings.iter() {
-		let u16data: Vec<u16> = string.encode_utf16().collect();
-		let size = u16data.len() * 2;
+	for &(ref string, encoding) in strings.iter() {
+		match encoding {
+			StringEncoding::Unicode => {
+				let u16data: Vec<u16> = string.encode_utf16().collect();
+				let size = u16data.len() * 2;
 
-		result
-			.write_u8(0xfe)
-			.map_err(|_| StringEncodeError("Failed to write file rec string header"))?;
+				result
+					.write_u8(0xfe)
+					.map_err(|_| StringEncodeError("Failed to write file rec string header"))?;
 
-		result
-			.write_i32::<LittleEndian>(-(size as i32))
-			.map_err(|_| StringEncodeError("Failed to write file rec string size"))?;
+				result
+					.write_i32::<LittleEndian>(-(size as i32))
+					.map_err(|_| StringEncodeError("Failed to write file rec string size"))?;
 
-		let start = result.len();
-		let end = start + size;
-		result.resize(end, 0);
+				let start = result.len();
+				let end = start + size;
+				result.resize(end, 0);
 
-		LittleEndian::write_u16_into(&u16data, &mut result[start..end]);
+				LittleEndian::write_u16_into(&u16data, &mut result[start..end]);
+			}
+			StringEncoding::Ansi => {
+				let bytes = encode_windows_1252(string).ok_or(StringEncodeError(
+					"File rec string is not representable as ANSI",
+				))?;
+
+				let header = if bytes.len() < 0xfd {
+					result.write_u8(bytes.len() as u8)
+				} else if bytes.len() <= 0xffff {
+					result
+						.write_u8(0xfd)
+						.and_then(|_| result.write_u16::<LittleEndian>(bytes.len() as u16))
+				} else {
+					result
+						.write_u8(0xfe)
+						.and_then(|_| result.write_i32::<LittleEndian>(bytes.len() as i32))
+				};
+
+				header.map_err(|_| StringEncodeError("Failed to write file rec string header"))?;
+				result.extend_from_slice(&bytes);
+			}
+		}
 	}
 
 	result
@@ -274,7 +498,7 @@ impl FileRec {
 
 	pub fn to_writer<'b>(&self, writer: &mut dyn Write) -> Result<(), FileRecWriteError<'b>> {
 		writer
-			.write_u16::<LittleEndian>(self.typ as u16)
+			.write_u16::<LittleEndian>(self.typ.value())
 			.map_err(|_| FileRecWriteError("Failed to write file rec typ to buffer"))?;
 
 		writer
@@ -292,22 +516,209 @@ impl FileRec {
 		Ok(())
 	}
 
+	/**
+	 * Size of this file rec in the decoded stream: typ, extra data,
+	 * data size and data.
+	 */
+	pub fn encoded_len(&self) -> usize {
+		2 + 4 + 4 + self.data.len()
+	}
+
+	/**
+	 * Decodes the paths of `DeleteDirOrFiles` and `DeleteFile` records.
+	 * Other record types have no paths.
+	 */
+	pub fn get_paths<'b>(&self) -> Result<Vec<String>, StringDecodeError<'b>> {
+		if !self.is_path_record() {
+			return Ok(vec![]);
+		}
+
+		decode_strings(&self.data)
+	}
+
+	pub fn is_path_record(&self) -> bool {
+		self.typ.is_path_record()
+	}
+
+	/**
+	 * Decodes the strings of any record type which stores a string table,
+	 * such as the keys and values of registry and INI records.
+	 * `CompiledCode` records pack Pascal Script bytecode into theirs, and
+	 * the payload of unknown types is unknown, so those have no strings.
+	 */
+	pub fn get_strings<'b>(&self) -> Result<Vec<String>, StringDecodeError<'b>> {
+		if !self.typ.has_strings() {
+			return Ok(vec![]);
+		}
+
+		decode_strings(&self.data)
+	}
+
+	pub fn validate_strings<'b>(&self) -> Result<(), StringDecodeError<'b>> {
+		validate_strings(&self.data)
+	}
+
+	/**
+	 * Whether the record's string table splits up into strings, whatever
+	 * those decode to.
+	 */
+	pub fn is_string_table_intact(&self) -> bool {
+		split_rec_strings(&self.data).is_ok()
+	}
+
+	/**
+	 * Guesses whether the record's paths were written in an encoding other
+	 * than the one we decode them with: paths which don't decode at all, or
+	 * where more than a quarter of the characters are suspicious, are
+	 * unlikely to be genuine.
+	 */
+	pub fn looks_misencoded(&self) -> bool {
+		let paths = match self.get_paths() {
+			Ok(paths) => paths,
+			Err(_) => return true,
+		};
+
+		let mut total = 0;
+		let mut suspicious = 0;
+
+		for c in paths.iter().flat_map(|p| p.chars()) {
+			total += 1;
+
+			if is_suspicious_char(c) {
+				suspicious += 1;
+			}
+		}
+
+		suspicious * 4 > total
+	}
+
+	/**
+	 * A best-effort, human readable rendering of the record's payload,
+	 * falling back to a hex dump when it can't be decoded.
+	 */
+	pub fn describe(&self) -> String {
+		let strings = match self.typ {
+			UninstallRecTyp::DeleteDirOrFiles
+			| UninstallRecTyp::DeleteFile
+			| UninstallRecTyp::RefreshFileAssoc
+			| UninstallRecTyp::MutexCheck => decode_strings(&self.data).ok(),
+			_ => None,
+		};
+
+		match (self.typ, strings) {
+			(UninstallRecTyp::MutexCheck, Some(names)) => format!("mutex: {}", names.join(", ")),
+			(_, Some(ref strings)) if strings.is_empty() => String::from("(no data)"),
+			(_, Some(strings)) => strings.join(", "),
+			(_, None) => hex_dump(&self.data),
+		}
+	}
+
+	pub fn extra_data(&self) -> u32 {
+		self.extra_data
+	}
+
+	pub fn with_extra_data(self, extra_data: u32) -> FileRec {
+		FileRec { extra_data, ..self }
+	}
+
+	/**
+	 * Replaces the paths of this record. Each path keeps the encoding of the
+	 * one it replaces; added paths take the encoding of the last one, or
+	 * Unicode if there was none.
+	 */
+	pub fn set_paths<'b>(&mut self, paths: &[String]) -> Result<(), StringEncodeError<'b>> {
+		let encodings: Vec<StringEncoding> = decode_rec_strings(&self.data)
+			.map(|strings| strings.into_iter().map(|(_, encoding)| encoding).collect())
+			.unwrap_or_default();
+
+		let strings: Vec<(String, StringEncoding)> = paths
+			.iter()
+			.enumerate()
+			.map(|(i, path)| {
+				let encoding = encodings
+					.get(i)
+					.or_else(|| encodings.last())
+					.copied()
+					.unwrap_or(StringEncoding::Unicode);
+
+				(path.clone(), encoding)
+			})
+			.collect();
+
+		self.data = encode_rec_strings(&strings)?;
+		Ok(())
+	}
+
+	/**
+	 * Renders `extra_data` as hex, followed by the names of the flags set,
+	 * for delete records. Bits without a name are kept in hex.
+	 */
+	pub fn describe_flags(&self) -> String {
+		let known: &[(u32, &str)] = match self.typ {
+			UninstallRecTyp::DeleteDirOrFiles => &DELETE_DIR_OR_FILES_FLAGS,
+			UninstallRecTyp::DeleteFile => &DELETE_FILE_FLAGS,
+			_ => &[],
+		};
+
+		let mut names: Vec<String> = vec![];
+		let mut rest = self.extra_data;
+
+		for &(bit, name) in known {
+			if self.extra_data & bit != 0 {
+				names.push(String::from(name));
+				rest &= !bit;
+			}
+		}
+
+		if names.is_empty() {
+			return format!("0x{:x}", self.extra_data);
+		}
+
+		if rest != 0 {
+			names.push(format!("0x{:x}", rest));
+		}
+
+		format!("0x{:x} {}", self.extra_data, names.join("|"))
+	}
+
+	pub fn to_json<'b>(&self) -> Result<String, StringDecodeError<'b>> {
+		let paths: Vec<String> = self
+			.get_paths()?
+			.iter()
+			.map(|p| to_json_string(p))
+			.collect();
+
+		Ok(format!(
+			"{{\"type\": {}, \"type_value\": {}, \"extra_data\": {}, \"paths\": [{}]}}",
+			to_json_string(self.typ.name()),
+			self.typ.value(),
+			self.extra_data,
+			paths.join(", ")
+		))
+	}
+
 	pub fn rebase(&self, update_path: &Path) -> Result<FileRec, Box<dyn error::Error>> {
-		let paths = decode_strings(&self.data)?;
+		let to = update_path.parent().ok_or(RebaseError)?;
+		self.rebase_with(update_path, to)
+	}
 
-		let from = update_path.to_str().ok_or(RebaseError)?;
-		let to = update_path
-			.parent()
-			.and_then(|p| p.to_str())
-			.ok_or(RebaseError)?;
+	/**
+	 * Moves every path under `from` to the same place under `to`. Trailing
+	 * backslashes on either prefix are ignored, as is the case of `from`.
+	 */
+	pub fn rebase_with(&self, from: &Path, to: &Path) -> Result<FileRec, Box<dyn error::Error>> {
+		let paths = decode_rec_strings(&self.data)?;
 
-		let rebased_paths: Vec<String> = paths
+		let from = from.to_str().ok_or(RebaseError)?.trim_end_matches('\\');
+		let to = to.to_str().ok_or(RebaseError)?.trim_end_matches('\\');
+
+		let rebased_paths: Vec<(String, StringEncoding)> = paths
 			.iter()
-			.map(|p| {
-				if let Some(p) = p.strip_prefix(from) {
-					format!("{to}{p}")
+			.map(|&(ref p, encoding)| {
+				if let Some(rest) = strip_path_prefix(p, from) {
+					(format!("{to}{rest}"), encoding)
 				} else {
-					p.clone()
+					(p.clone(), encoding)
 				}
 			})
 			.collect();
@@ -315,7 +726,277 @@ impl FileRec {
 		Ok(FileRec {
 			typ: self.typ,
 			extra_data: self.extra_data,
-			data: encode_strings(&rebased_paths)?,
+			data: encode_rec_strings(&rebased_paths)?,
 		})
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/**
+	 * A record as it would be read from a log.
+	 */
+	fn rec(typ: u16, extra_data: u32, data: &[u8]) -> FileRec {
+		let mut bytes = vec![];
+		bytes.write_u16::<LittleEndian>(typ).unwrap();
+		bytes.write_u32::<LittleEndian>(extra_data).unwrap();
+		bytes.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+		bytes.extend_from_slice(data);
+
+		FileRec::from_reader(&mut &bytes[..]).unwrap()
+	}
+
+	/**
+	 * A string table entry holding `units` as UTF-16, well-formed or not.
+	 */
+	fn unicode(units: &[u16]) -> Vec<u8> {
+		let mut bytes = vec![0xfe];
+		bytes
+			.write_i32::<LittleEndian>(-(units.len() as i32 * 2))
+			.unwrap();
+
+		for &unit in units {
+			bytes.write_u16::<LittleEndian>(unit).unwrap();
+		}
+
+		bytes
+	}
+
+	fn unicode_str(value: &str) -> Vec<u8> {
+		unicode(&value.encode_utf16().collect::<Vec<u16>>())
+	}
+
+	fn table(strings: &[Vec<u8>]) -> Vec<u8> {
+		let mut bytes: Vec<u8> = strings.concat();
+		bytes.push(0xff);
+		bytes
+	}
+
+	#[test]
+	fn validate_strings_flags_unpaired_surrogates() {
+		let valid = rec(0x82, 0, &table(&[unicode_str("C:\\Code\\\u{1f600}.txt")]));
+		assert!(valid.validate_strings().is_ok());
+
+		// a high surrogate with no low one after it
+		let lone = rec(0x82, 0, &table(&[unicode(&[0x43, 0xd83d, 0x3a])]));
+		assert!(lone.validate_strings().is_err());
+		assert!(lone.get_paths().is_err());
+
+		// a low surrogate on its own
+		let lone = rec(0x82, 0, &table(&[unicode_str("C:\\"), unicode(&[0xde00])]));
+		assert!(lone.validate_strings().is_err());
+	}
+
+	#[test]
+	fn validate_strings_flags_odd_lengths() {
+		let mut odd = vec![0xfe];
+		odd.write_i32::<LittleEndian>(-3).unwrap();
+		odd.extend_from_slice(&[0x43, 0x00, 0x3a]);
+
+		let rec = rec(0x82, 0, &table(&[odd]));
+		assert!(rec.validate_strings().is_err());
+		assert!(rec.get_paths().is_err());
+	}
+
+	#[test]
+	fn rebase_with_only_moves_whole_components() {
+		let rec = rec(
+			0x82,
+			0,
+			&table(&[
+				unicode_str("C:\\Code\\_\\Code.exe"),
+				unicode_str("c:\\code\\_\\resources"),
+				unicode_str("C:\\Code\\__\\Code.exe"),
+			]),
+		);
+
+		let rebased = rec
+			.rebase_with(Path::new("C:\\Code\\_\\"), Path::new("C:\\Code"))
+			.unwrap();
+
+		assert_eq!(
+			rebased.get_paths().unwrap(),
+			vec![
+				"C:\\Code\\Code.exe",
+				"C:\\Code\\resources",
+				"C:\\Code\\__\\Code.exe",
+			]
+		);
+	}
+
+	#[test]
+	fn rebase_with_moves_between_drives_and_into_subfolders() {
+		let rec = rec(
+			0x81,
+			0,
+			&table(&[
+				unicode_str("C:\\Code"),
+				unicode_str("C:\\Code\\resources\\app"),
+				unicode_str("C:\\Users\\me\\.vscode"),
+			]),
+		);
+
+		let moved = rec
+			.rebase_with(Path::new("C:\\Code"), Path::new("D:\\Apps\\Code\\"))
+			.unwrap();
+		assert_eq!(
+			moved.get_paths().unwrap(),
+			vec![
+				"D:\\Apps\\Code",
+				"D:\\Apps\\Code\\resources\\app",
+				"C:\\Users\\me\\.vscode",
+			]
+		);
+
+		let nested = rec
+			.rebase_with(Path::new("C:\\Code"), Path::new("C:\\Code\\old"))
+			.unwrap();
+		assert_eq!(
+			nested.get_paths().unwrap(),
+			vec![
+				"C:\\Code\\old",
+				"C:\\Code\\old\\resources\\app",
+				"C:\\Users\\me\\.vscode",
+			]
+		);
+	}
+
+	#[test]
+	fn set_paths_keeps_each_encoding() {
+		let ansi = |value: &str| [&[value.len() as u8][..], value.as_bytes()].concat();
+
+		let mut edited =
+			rec(0x82, 0, &table(&[ansi("C:\\a"), unicode_str("C:\\b")])).with_extra_data(0x100);
+		let paths: Vec<String> = ["D:\\x", "D:\\y", "D:\\z"]
+			.iter()
+			.map(|p| p.to_string())
+			.collect();
+		edited.set_paths(&paths).unwrap();
+
+		assert_eq!(edited.get_paths().unwrap(), paths);
+		assert_eq!(edited.extra_data(), 0x100);
+
+		// added paths take the encoding of the last one
+		let expected = rec(
+			0x82,
+			0x100,
+			&table(&[ansi("D:\\x"), unicode_str("D:\\y"), unicode_str("D:\\z")]),
+		);
+		assert!(edited == expected);
+
+		// ANSI strings can't hold just anything
+		let emoji = vec![String::from("C:\\\u{1f600}")];
+		assert!(edited.set_paths(&emoji).is_err());
+	}
+
+	#[test]
+	fn record_types_are_classified() {
+		let known = [
+			0x01, 0x10, 0x11, 0x20, 0x80, 0x81, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+			0x8A, 0x8B, 0x8C,
+		];
+
+		for &value in known.iter().chain(&[0x99]) {
+			let typ = UninstallRecTyp::from(value);
+			let paths = value == 0x81 || value == 0x82;
+			let strings = value != 0x20 && value != 0x99;
+
+			assert_eq!(typ.is_path_record(), paths, "{}", typ.name());
+			assert_eq!(typ.has_strings(), strings, "{}", typ.name());
+			assert_eq!(rec(value, 0, &[]).is_path_record(), paths);
+		}
+	}
+
+	#[test]
+	fn unknown_types_are_kept_as_they_are() {
+		assert!(UninstallRecTyp::from(0x99) == UninstallRecTyp::Unknown(0x99));
+		assert_eq!(UninstallRecTyp::from(0x99).value(), 0x99);
+		assert_eq!(UninstallRecTyp::from(0x99).name(), "Unknown");
+
+		let data = [0x01, 0x02, 0x03];
+		let unknown = rec(0x99, 7, &data);
+		assert!(!unknown.is_path_record());
+		assert!(unknown.get_paths().unwrap().is_empty());
+
+		let mut bytes = vec![];
+		unknown.to_writer(&mut bytes).unwrap();
+		assert_eq!(
+			bytes,
+			[&[0x99, 0x00, 7, 0, 0, 0, 3, 0, 0, 0][..], &data[..]].concat()
+		);
+	}
+
+	#[test]
+	fn mixed_ansi_and_unicode_strings_round_trip() {
+		let long = format!("C:\\{}", "a".repeat(300));
+
+		let mut ansi_long = vec![0xfd];
+		ansi_long
+			.write_u16::<LittleEndian>(long.len() as u16)
+			.unwrap();
+		ansi_long.extend_from_slice(long.as_bytes());
+
+		// "C:\café" in Windows-1252
+		let ansi_short = [&[7][..], b"C:\\caf", &[0xe9]].concat();
+
+		let data = table(&[ansi_long, unicode_str("C:\\Code\\\u{1f600}"), ansi_short]);
+		let mixed = rec(0x82, 0, &data);
+
+		assert_eq!(
+			mixed.get_paths().unwrap(),
+			vec![
+				long.clone(),
+				String::from("C:\\Code\\\u{1f600}"),
+				String::from("C:\\café")
+			]
+		);
+
+		// each string is written back with the encoding it was read with
+		let rewritten = mixed
+			.rebase_with(Path::new("Z:\\elsewhere"), Path::new("Y:\\"))
+			.unwrap();
+		assert!(rewritten == mixed);
+	}
+
+	#[test]
+	fn equal_records_hash_equally() {
+		use std::collections::hash_map::DefaultHasher;
+		use std::hash::{Hash, Hasher};
+
+		let hash = |rec: &FileRec| {
+			let mut hasher = DefaultHasher::new();
+			rec.hash(&mut hasher);
+			hasher.finish()
+		};
+
+		let data = table(&[unicode_str("C:\\Code\\Code.exe")]);
+		let a = rec(0x82, 0, &data);
+		let b = rec(0x82, 0, &data);
+		assert!(a == b);
+		assert_eq!(hash(&a), hash(&b));
+
+		// a different type, flags or path each make a different record
+		let others = [
+			rec(0x81, 0, &data),
+			rec(0x82, 2, &data),
+			rec(0x82, 0, &table(&[unicode_str("C:\\Code\\code.exe")])),
+		];
+
+		for other in &others {
+			assert!(*other != a);
+			assert_ne!(hash(other), hash(&a));
+		}
+	}
+
+	#[test]
+	fn describe_shows_mutex_names() {
+		let mutex = rec(0x8C, 0, &table(&[unicode_str("vscodeuser")]));
+		assert_eq!(mutex.describe(), "mutex: vscodeuser");
+
+		// payloads which aren't a string table are dumped as hex
+		let garbled = rec(0x8C, 0, &[0x01, 0xab]);
+		assert_eq!(garbled.describe(), "01 ab");
+	}
+}
@@ -5,6 +5,7 @@
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use crc::{Crc, CRC_32_ISO_HDLC};
+use std::convert::TryFrom;
 use std::io::prelude::*;
 use std::string::String;
 use std::{error, fmt};
@@ -66,6 +67,7 @@ pub struct Header {
 	pub num_recs: usize,
 	pub end_offset: u32,
 	flags: u32,
+	reserved: [u8; 108],
 	crc: u32,
 }
 
@@ -88,6 +90,17 @@ impl fmt::Debug for Header {
 
 impl Header {
 	pub fn from_reader<'a>(reader: &mut dyn Read) -> Result<Header, HeaderParseError<'a>> {
+		Header::read(reader, true)
+	}
+
+	/**
+	 * Like `from_reader`, but doesn't check the header's crc32.
+	 */
+	pub fn from_reader_lenient<'a>(reader: &mut dyn Read) -> Result<Header, HeaderParseError<'a>> {
+		Header::read(reader, false)
+	}
+
+	fn read<'a>(reader: &mut dyn Read, check_crc: bool) -> Result<Header, HeaderParseError<'a>> {
 		let mut buf = [0; HEADER_SIZE];
 		reader
 			.read_exact(&mut buf)
@@ -121,7 +134,7 @@ impl Header {
 			.read_u32::<LittleEndian>()
 			.map_err(|_| HeaderParseError("Failed to parse header crc"))?;
 
-		if CRC32.checksum(&buf[..HEADER_SIZE - 4]) != crc {
+		if check_crc && CRC32.checksum(&buf[..HEADER_SIZE - 4]) != crc {
 			return Err(HeaderParseError("CRC32 check failed"));
 		}
 
@@ -135,6 +148,11 @@ impl Header {
 			return Err(HeaderParseError("Header version not supported"));
 		}
 
+		// the records start right after the header
+		if (end_offset as usize) < HEADER_SIZE {
+			return Err(HeaderParseError("Header end offset is within the header"));
+		}
+
 		Ok(Header {
 			id,
 			app_id,
@@ -143,10 +161,56 @@ impl Header {
 			num_recs,
 			end_offset,
 			flags,
+			reserved,
 			crc,
 		})
 	}
 
+	pub fn app_id(&self) -> &str {
+		&self.app_id
+	}
+
+	pub fn app_name(&self) -> &str {
+		&self.app_name
+	}
+
+	pub fn version(&self) -> i32 {
+		self.version
+	}
+
+	pub fn flags(&self) -> u32 {
+		self.flags
+	}
+
+	pub fn is_64bit(&self) -> bool {
+		self.id == HEADER_ID_64
+	}
+
+	pub fn to_json(&self) -> String {
+		format!(
+			"{{\"id\": {}, \"app_id\": {}, \"app_name\": {}, \"version\": {}, \"num_recs\": {}, \"end_offset\": {}, \"flags\": {}, \"crc\": {}}}",
+			strings::to_json_string(&self.id),
+			strings::to_json_string(&self.app_id),
+			strings::to_json_string(&self.app_name),
+			self.version,
+			self.num_recs,
+			self.end_offset,
+			self.flags,
+			self.crc,
+		)
+	}
+
+	/**
+	 * Sets the end offset, failing rather than wrapping around when it
+	 * doesn't fit the header's 32 bits.
+	 */
+	pub fn set_end_offset<'a>(&mut self, end_offset: u64) -> Result<(), HeaderWriteError<'a>> {
+		self.end_offset = u32::try_from(end_offset)
+			.map_err(|_| HeaderWriteError("End offset does not fit in 32 bits"))?;
+
+		Ok(())
+	}
+
 	pub fn to_writer<'a>(&self, writer: &mut dyn Write) -> Result<(), HeaderWriteError<'a>> {
 		let mut buf = [0; HEADER_SIZE];
 		{
@@ -172,9 +236,9 @@ impl Header {
 				.write_u32::<LittleEndian>(self.flags)
 				.map_err(|_| HeaderWriteError("Failed to write header flags to buffer"))?;
 
-			let reserved = vec![0; 108];
+			// written back as read, in case a newer Inno Setup uses it
 			buf_writer
-				.write_all(&reserved)
+				.write_all(&self.reserved)
 				.map_err(|_| HeaderWriteError("Failed to write header reserved to buffer"))?;
 		}
 
@@ -195,3 +259,67 @@ impl Header {
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn header_bytes(reserved: u8, end_offset: u32) -> Vec<u8> {
+		let mut buf = vec![0u8; HEADER_SIZE];
+		buf[..HEADER_ID_32.len()].copy_from_slice(HEADER_ID_32.as_bytes());
+		buf[64..72].copy_from_slice(b"{app-id}");
+		buf[192..196].copy_from_slice(b"Code");
+
+		{
+			let mut writer = &mut buf[320..336];
+			writer
+				.write_i32::<LittleEndian>(HIGHEST_SUPPORTED_VERSION)
+				.unwrap();
+			writer.write_u32::<LittleEndian>(3).unwrap();
+			writer.write_u32::<LittleEndian>(end_offset).unwrap();
+			writer.write_u32::<LittleEndian>(0x10).unwrap();
+		}
+
+		for (i, b) in buf[336..HEADER_SIZE - 4].iter_mut().enumerate() {
+			*b = reserved.wrapping_add(i as u8);
+		}
+
+		let crc = CRC32.checksum(&buf[..HEADER_SIZE - 4]);
+		(&mut buf[HEADER_SIZE - 4..])
+			.write_u32::<LittleEndian>(crc)
+			.unwrap();
+		buf
+	}
+
+	#[test]
+	fn reserved_bytes_round_trip() {
+		let bytes = header_bytes(0xa5, HEADER_SIZE as u32);
+		let header = Header::from_reader(&mut &bytes[..]).unwrap();
+
+		let mut written = Vec::new();
+		header.to_writer(&mut written).unwrap();
+
+		assert_eq!(written, bytes);
+	}
+
+	#[test]
+	fn end_offsets_must_fit_in_32_bits() {
+		let bytes = header_bytes(0, HEADER_SIZE as u32);
+		let mut header = Header::from_reader(&mut &bytes[..]).unwrap();
+
+		header.set_end_offset(u32::MAX as u64).unwrap();
+		assert_eq!(header.end_offset, u32::MAX);
+
+		let err = header.set_end_offset(u32::MAX as u64 + 1).unwrap_err();
+		assert_eq!(err.0, "End offset does not fit in 32 bits");
+		assert_eq!(header.end_offset, u32::MAX);
+	}
+
+	#[test]
+	fn end_offsets_within_the_header_are_refused() {
+		let bytes = header_bytes(0, HEADER_SIZE as u32 - 1);
+		let err = Header::from_reader(&mut &bytes[..]).unwrap_err();
+
+		assert_eq!(err.0, "Header end offset is within the header");
+	}
+}
@@ -5,20 +5,79 @@
 
 use std::ffi::c_void;
 use std::path::{Path, PathBuf};
-use std::{error, io, mem, ptr, thread, time};
+use std::{cmp, error, fmt, io, mem, ptr, thread, time};
 use strings::from_utf16;
+use windows_sys::Win32::System::Diagnostics::ToolHelp::PROCESSENTRY32W;
 use {slog, util};
 
+#[derive(Debug)]
 pub struct RunningProcess {
 	pub name: String,
 	pub id: u32,
+	// full executable path, if the process could be queried for it
+	pub path: Option<PathBuf>,
 }
 
-pub fn get_running_processes() -> Result<Vec<RunningProcess>, io::Error> {
+impl RunningProcess {
+	/**
+	 * Whether this process may be running from `path`; processes whose
+	 * path couldn't be determined are assumed to match.
+	 */
+	fn may_be_at(&self, path: &Path) -> bool {
+		self.path.as_ref().is_none_or(|p| p == path)
+	}
+}
+
+/**
+ * The processes which could not be killed, each with the reason why.
+ */
+#[derive(Debug)]
+pub struct KillError(pub Vec<(RunningProcess, String)>);
+
+impl fmt::Display for KillError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let processes: Vec<String> = self
+			.0
+			.iter()
+			.map(|(process, err)| {
+				format!(
+					"{} (pid {}, {}): {}",
+					process.name,
+					process.id,
+					process
+						.path
+						.as_ref()
+						.map_or(String::from("unknown path"), |p| p.display().to_string()),
+					err
+				)
+			})
+			.collect();
+
+		write!(
+			f,
+			"Could not terminate these processes, please close them: {}",
+			processes.join("; ")
+		)
+	}
+}
+
+impl error::Error for KillError {
+	fn description(&self) -> &str {
+		"KillError"
+	}
+
+	fn cause(&self) -> Option<&dyn error::Error> {
+		None
+	}
+}
+
+/**
+ * Walks a snapshot of all running processes, calling `f` for each entry.
+ */
+pub fn for_each_process<F: FnMut(&PROCESSENTRY32W)>(mut f: F) -> Result<(), io::Error> {
 	use windows_sys::Win32::Foundation::{CloseHandle, INVALID_HANDLE_VALUE};
 	use windows_sys::Win32::System::Diagnostics::ToolHelp::{
-		CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W,
-		TH32CS_SNAPPROCESS,
+		CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, TH32CS_SNAPPROCESS,
 	};
 
 	unsafe {
@@ -55,16 +114,8 @@ pub fn get_running_processes() -> Result<Vec<RunningProcess>, io::Error> {
 			));
 		}
 
-		let mut result: Vec<RunningProcess> = vec![];
-
 		loop {
-			result.push(RunningProcess {
-				name: from_utf16(&pe32.szExeFile).map_err(|e| {
-					CloseHandle(handle);
-					e
-				})?,
-				id: pe32.th32ProcessID,
-			});
+			f(&pe32);
 
 			if Process32NextW(handle, &mut pe32) == 0 {
 				CloseHandle(handle);
@@ -72,23 +123,204 @@ pub fn get_running_processes() -> Result<Vec<RunningProcess>, io::Error> {
 			}
 		}
 
-		Ok(result)
+		Ok(())
+	}
+}
+
+/**
+ * Collects the running processes accepted by `predicate`, without
+ * allocating anything for the ones it rejects.
+ */
+pub fn find_running_processes<P: FnMut(&PROCESSENTRY32W) -> bool>(
+	mut predicate: P,
+) -> Result<Vec<RunningProcess>, io::Error> {
+	let mut result: Vec<RunningProcess> = vec![];
+	let mut error: Option<io::Error> = None;
+
+	for_each_process(|pe32| {
+		if error.is_some() || !predicate(pe32) {
+			return;
+		}
+
+		match from_utf16(&pe32.szExeFile) {
+			Ok(name) => result.push(RunningProcess {
+				name,
+				id: pe32.th32ProcessID,
+				path: get_process_path(pe32.th32ProcessID),
+			}),
+			Err(err) => error = Some(err),
+		}
+	})?;
+
+	match error {
+		Some(err) => Err(err),
+		None => Ok(result),
+	}
+}
+
+pub fn get_running_processes() -> Result<Vec<RunningProcess>, io::Error> {
+	find_running_processes(|_| true)
+}
+
+/**
+ * Gets the full executable path of a process, or `None` if it can't be
+ * queried, e.g. because access is denied.
+ */
+fn get_process_path(pid: u32) -> Option<PathBuf> {
+	use windows_sys::Win32::Foundation::{CloseHandle, MAX_PATH};
+	use windows_sys::Win32::System::ProcessStatus::K32GetModuleFileNameExW;
+	use windows_sys::Win32::System::Threading::{
+		OpenProcess, PROCESS_QUERY_INFORMATION, PROCESS_VM_READ,
+	};
+
+	unsafe {
+		let handle = OpenProcess(PROCESS_QUERY_INFORMATION | PROCESS_VM_READ, 0, pid);
+
+		if ptr::eq(handle as *mut c_void, ptr::null()) {
+			return None;
+		}
+
+		let mut raw_path = [0u16; MAX_PATH as usize];
+		let len = K32GetModuleFileNameExW(handle, mem::zeroed(), raw_path.as_mut_ptr(), MAX_PATH)
+			as usize;
+
+		CloseHandle(handle);
+
+		if len == 0 {
+			return None;
+		}
+
+		from_utf16(&raw_path[0..len]).ok().map(PathBuf::from)
+	}
+}
+
+fn exe_name_matches(pe32: &PROCESSENTRY32W, name: &[u16]) -> bool {
+	let len = pe32
+		.szExeFile
+		.iter()
+		.position(|&x| x == 0)
+		.unwrap_or(pe32.szExeFile.len());
+
+	pe32.szExeFile[..len] == *name
+}
+
+/**
+ * Posts WM_CLOSE to every top-level window owned by a process, the way a
+ * regular shutdown would. Returns how many windows were asked to close.
+ */
+fn close_process_windows(pid: u32) -> usize {
+	use windows_sys::Win32::Foundation::{BOOL, HWND, LPARAM};
+	use windows_sys::Win32::UI::WindowsAndMessaging::{
+		EnumWindows, GetWindowThreadProcessId, PostMessageW, WM_CLOSE,
+	};
+
+	unsafe extern "system" fn callback(hwnd: HWND, l: LPARAM) -> BOOL {
+		let state = &mut *(l as *mut (u32, usize));
+		let mut window_pid: u32 = 0;
+		GetWindowThreadProcessId(hwnd, &mut window_pid);
+
+		if window_pid == state.0 && PostMessageW(hwnd, WM_CLOSE, 0, 0) != 0 {
+			state.1 += 1;
+		}
+
+		// keep enumerating
+		1
+	}
+
+	let mut state: (u32, usize) = (pid, 0);
+
+	unsafe {
+		EnumWindows(Some(callback), &mut state as *mut (u32, usize) as LPARAM);
+	}
+
+	state.1
+}
+
+// MAXIMUM_WAIT_OBJECTS, how many handles a single wait can take
+const MAX_WAIT_HANDLES: usize = 64;
+
+/**
+ * Waits up to `timeout` for all `processes` to exit, waking up as soon as
+ * they have, rather than polling. Processes which are gone already count
+ * as exited. Returns whether they all exited, or `None` if they can't be
+ * waited on, e.g. because one can't be opened, in which case the caller
+ * should poll instead.
+ */
+fn wait_for_exit(processes: &[RunningProcess], timeout: time::Duration) -> Option<bool> {
+	use windows_sys::Win32::Foundation::{
+		CloseHandle, GetLastError, ERROR_INVALID_PARAMETER, HANDLE, WAIT_FAILED, WAIT_TIMEOUT,
+	};
+	use windows_sys::Win32::System::Threading::{
+		OpenProcess, WaitForMultipleObjects, PROCESS_SYNCHRONIZE,
+	};
+
+	if processes.len() > MAX_WAIT_HANDLES {
+		return None;
+	}
+
+	let mut handles: Vec<HANDLE> = Vec::with_capacity(processes.len());
+	let close_all = |handles: &[HANDLE]| {
+		for &handle in handles {
+			unsafe {
+				CloseHandle(handle);
+			}
+		}
+	};
+
+	for process in processes {
+		let handle = unsafe { OpenProcess(PROCESS_SYNCHRONIZE, 0, process.id) };
+
+		if handle == 0 {
+			// an invalid parameter means there's no such process anymore
+			if unsafe { GetLastError() } == ERROR_INVALID_PARAMETER {
+				continue;
+			}
+
+			close_all(&handles);
+			return None;
+		}
+
+		handles.push(handle);
+	}
+
+	if handles.is_empty() {
+		return Some(true);
+	}
+
+	let millis = cmp::min(timeout.as_millis(), u32::MAX as u128) as u32;
+	let result =
+		unsafe { WaitForMultipleObjects(handles.len() as u32, handles.as_ptr(), 1, millis) };
+
+	close_all(&handles);
+
+	match result {
+		WAIT_TIMEOUT => Some(false),
+		WAIT_FAILED => None,
+		_ => Some(true),
 	}
 }
 
 /**
  * Kills a running process, if its path is the same as the provided one.
+ * When `grace` is non-zero, the process' windows are first asked to close
+ * and the process is only terminated if it is still alive after `grace`.
+ * Returns whether the process was ours and is gone, or would be in a dry
+ * run; processes which are skipped return `false`.
  */
 fn kill_process_if(
 	log: &slog::Logger,
 	process: &RunningProcess,
 	path: &Path,
-) -> Result<(), Box<dyn error::Error>> {
-	use windows_sys::Win32::Foundation::{CloseHandle, MAX_PATH};
+	grace: time::Duration,
+	dry_run: bool,
+) -> Result<bool, Box<dyn error::Error>> {
+	use windows_sys::Win32::Foundation::{
+		CloseHandle, GetLastError, ERROR_ACCESS_DENIED, MAX_PATH, WAIT_OBJECT_0,
+	};
 	use windows_sys::Win32::System::ProcessStatus::K32GetModuleFileNameExW;
 	use windows_sys::Win32::System::Threading::{
-		OpenProcess, TerminateProcess, PROCESS_QUERY_INFORMATION, PROCESS_TERMINATE,
-		PROCESS_VM_READ,
+		OpenProcess, TerminateProcess, WaitForSingleObject, PROCESS_QUERY_INFORMATION,
+		PROCESS_SYNCHRONIZE, PROCESS_TERMINATE, PROCESS_VM_READ,
 	};
 
 	info!(
@@ -99,12 +331,22 @@ fn kill_process_if(
 	unsafe {
 		// https://msdn.microsoft.com/en-us/library/windows/desktop/ms684320(v=vs.85).aspx
 		let handle = OpenProcess(
-			PROCESS_QUERY_INFORMATION | PROCESS_VM_READ | PROCESS_TERMINATE,
+			PROCESS_QUERY_INFORMATION | PROCESS_VM_READ | PROCESS_TERMINATE | PROCESS_SYNCHRONIZE,
 			0,
 			process.id,
 		);
 
 		if ptr::eq(handle as *mut c_void, ptr::null()) {
+			// protected processes can't be opened, and aren't ours to kill anyway
+			if GetLastError() == ERROR_ACCESS_DENIED {
+				warn!(
+					log,
+					"Access denied opening {}, pid {}, skipping", process.name, process.id
+				);
+
+				return Ok(false);
+			}
+
 			return Err(io::Error::new(
 				io::ErrorKind::Other,
 				format!(
@@ -115,28 +357,65 @@ fn kill_process_if(
 			.into());
 		}
 
-		let mut raw_path = [0u16; MAX_PATH as usize];
-		let len = K32GetModuleFileNameExW(handle, mem::zeroed(), raw_path.as_mut_ptr(), MAX_PATH)
-			as usize;
+		let process_path = match process.path {
+			Some(ref process_path) => process_path.clone(),
+			None => {
+				let mut raw_path = [0u16; MAX_PATH as usize];
+				let len =
+					K32GetModuleFileNameExW(handle, mem::zeroed(), raw_path.as_mut_ptr(), MAX_PATH)
+						as usize;
 
-		if len == 0 {
-			CloseHandle(handle);
+				// without a path we can't tell whether it's ours, so leave it be
+				// rather than failing the kill of every other process
+				if len == 0 {
+					warn!(
+						log,
+						"Failed to get file name of {}, pid {}, skipping: {}",
+						process.name,
+						process.id,
+						util::get_last_error_message()?
+					);
 
-			return Err(io::Error::new(
-				io::ErrorKind::Other,
-				format!(
-					"Failed to get process file name: {}",
-					util::get_last_error_message()?
-				),
-			)
-			.into());
-		}
+					CloseHandle(handle);
+					return Ok(false);
+				}
 
-		let process_path = PathBuf::from(from_utf16(&raw_path[0..len])?);
+				PathBuf::from(from_utf16(&raw_path[0..len])?)
+			}
+		};
 
 		if process_path != path {
 			CloseHandle(handle);
-			return Ok(());
+			return Ok(false);
+		}
+
+		if dry_run {
+			info!(log, "WOULD kill {}, pid {}", process.name, process.id);
+
+			CloseHandle(handle);
+			return Ok(true);
+		}
+
+		if grace > time::Duration::from_secs(0) && close_process_windows(process.id) > 0 {
+			info!(
+				log,
+				"Asked {}, pid {}, to close, waiting up to {:?}...",
+				process.name,
+				process.id,
+				grace
+			);
+
+			let millis = cmp::min(grace.as_millis(), u32::MAX as u128) as u32;
+
+			if WaitForSingleObject(handle, millis) == WAIT_OBJECT_0 {
+				info!(
+					log,
+					"{} closed gracefully, pid {}", process.name, process.id
+				);
+
+				CloseHandle(handle);
+				return Ok(true);
+			}
 		}
 
 		info!(
@@ -144,8 +423,12 @@ fn kill_process_if(
 			"Found {} running, pid {}, attempting to kill...", process.name, process.id
 		);
 
-		if TerminateProcess(handle, 0).is_negative() {
-			return Err(io::Error::new(io::ErrorKind::Other, "Failed to kill process").into());
+		if TerminateProcess(handle, 0) == 0 {
+			let last_error = GetLastError();
+			let message = util::get_last_error_message();
+			CloseHandle(handle);
+
+			return terminate_failed(log, process, last_error, message);
 		}
 
 		info!(
@@ -154,25 +437,291 @@ fn kill_process_if(
 		);
 
 		CloseHandle(handle);
-		Ok(())
+		Ok(true)
+	}
+}
+
+/**
+ * Handles `process` failing to terminate with `last_error`, described by
+ * `message`.
+ */
+fn terminate_failed(
+	log: &slog::Logger,
+	process: &RunningProcess,
+	last_error: u32,
+	message: Result<String, Box<dyn error::Error>>,
+) -> Result<bool, Box<dyn error::Error>> {
+	use windows_sys::Win32::Foundation::ERROR_ACCESS_DENIED;
+
+	// like when opening it, a protected process isn't ours to kill
+	if last_error == ERROR_ACCESS_DENIED {
+		warn!(
+			log,
+			"Access denied killing {}, pid {}, skipping", process.name, process.id
+		);
+
+		return Ok(false);
+	}
+
+	Err(io::Error::other(format!("Failed to kill process: {}", message?)).into())
+}
+
+/**
+ * When a process was created, as a FILETIME, or `None` if it can't be
+ * queried.
+ */
+fn process_creation_time(pid: u32) -> Option<u64> {
+	use windows_sys::Win32::Foundation::{CloseHandle, FILETIME};
+	use windows_sys::Win32::System::Threading::{
+		GetProcessTimes, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+	};
+
+	unsafe {
+		let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+
+		if handle == 0 {
+			return None;
+		}
+
+		let mut creation: FILETIME = mem::zeroed();
+		let mut exit: FILETIME = mem::zeroed();
+		let mut kernel: FILETIME = mem::zeroed();
+		let mut user: FILETIME = mem::zeroed();
+		let result = GetProcessTimes(handle, &mut creation, &mut exit, &mut kernel, &mut user);
+
+		CloseHandle(handle);
+
+		if result == 0 {
+			return None;
+		}
+
+		Some(((creation.dwHighDateTime as u64) << 32) | creation.dwLowDateTime as u64)
+	}
+}
+
+/**
+ * The Terminal Services session `pid` runs in, or `None` if it can't be
+ * queried.
+ */
+fn session_id(pid: u32) -> Option<u32> {
+	use windows_sys::Win32::System::RemoteDesktop::ProcessIdToSessionId;
+
+	let mut session: u32 = 0;
+
+	if unsafe { ProcessIdToSessionId(pid, &mut session) } == 0 {
+		return None;
+	}
+
+	Some(session)
+}
+
+/**
+ * Whether `pid` is known to run in another session than `own_session`.
+ * Processes whose session can't be determined are assumed to be ours.
+ */
+fn in_other_session(pid: u32, own_session: Option<u32>) -> bool {
+	match (own_session, session_id(pid)) {
+		(Some(own), Some(theirs)) => own != theirs,
+		_ => false,
+	}
+}
+
+/**
+ * Finds all descendants of `root_pid`, parents before their children.
+ * Process ids get reused, so a process only counts as a child if it was
+ * created after its parent; ids already visited are skipped, so that
+ * cycles can't loop forever. Without the root's creation time, its
+ * children can't be told apart from reused ids, so none are found.
+ */
+fn find_descendants(root_pid: u32) -> Result<Vec<u32>, io::Error> {
+	use std::collections::{HashSet, VecDeque};
+
+	let mut entries: Vec<(u32, u32)> = vec![];
+
+	for_each_process(|pe32| entries.push((pe32.th32ProcessID, pe32.th32ParentProcessID)))?;
+
+	let root_created = match process_creation_time(root_pid) {
+		Some(created) => created,
+		None => return Ok(vec![]),
+	};
+
+	let mut result: Vec<u32> = vec![];
+	let mut visited: HashSet<u32> = HashSet::new();
+	let mut queue: VecDeque<(u32, u64)> = VecDeque::new();
+
+	visited.insert(root_pid);
+	queue.push_back((root_pid, root_created));
+
+	while let Some((parent, parent_created)) = queue.pop_front() {
+		for &(pid, parent_pid) in &entries {
+			if parent_pid != parent || visited.contains(&pid) {
+				continue;
+			}
+
+			match process_creation_time(pid) {
+				Some(created) if created >= parent_created => {
+					visited.insert(pid);
+					result.push(pid);
+					queue.push_back((pid, created));
+				}
+				_ => {}
+			}
+		}
+	}
+
+	Ok(result)
+}
+
+/**
+ * Terminates a process by id; processes which are gone already, or which
+ * we may not open, are left alone.
+ */
+fn terminate_process(
+	log: &slog::Logger,
+	pid: u32,
+	dry_run: bool,
+) -> Result<(), Box<dyn error::Error>> {
+	use windows_sys::Win32::Foundation::CloseHandle;
+	use windows_sys::Win32::System::Threading::{OpenProcess, TerminateProcess, PROCESS_TERMINATE};
+
+	if dry_run {
+		info!(log, "WOULD kill child process, pid {}", pid);
+		return Ok(());
+	}
+
+	unsafe {
+		let handle = OpenProcess(PROCESS_TERMINATE, 0, pid);
+
+		if handle == 0 {
+			warn!(log, "Could not open child process, pid {}, skipping", pid);
+			return Ok(());
+		}
+
+		let result = TerminateProcess(handle, 0);
+		CloseHandle(handle);
+
+		if result == 0 {
+			return Err(io::Error::other(format!(
+				"Failed to kill child process {}: {}",
+				pid,
+				util::get_last_error_message()?
+			))
+			.into());
+		}
+	}
+
+	info!(log, "Killed child process, pid {}", pid);
+	Ok(())
+}
+
+/**
+ * Kills `process`, like `kill_process_if`, along with all of its
+ * descendants. These are looked up first, while they can still be traced
+ * back to `process`, but only killed if `process` was.
+ */
+fn kill_process_tree(
+	log: &slog::Logger,
+	process: &RunningProcess,
+	path: &Path,
+	grace: time::Duration,
+	dry_run: bool,
+) -> Result<bool, Box<dyn error::Error>> {
+	let descendants = find_descendants(process.id)?;
+
+	// the children of a process we left alone aren't ours to kill either
+	if !kill_process_if(log, process, path, grace, dry_run)? {
+		return Ok(false);
+	}
+
+	// deepest first
+	for pid in descendants.into_iter().rev() {
+		terminate_process(log, pid, dry_run)?;
+	}
+
+	Ok(true)
+}
+
+pub struct WaitOptions {
+	// fail, rather than kill, if processes are still running after the wait
+	pub strict: bool,
+	// how long to wait for processes to exit on their own
+	pub timeout: time::Duration,
+	// how long to wait after asking a process' windows to close, before
+	// terminating it; zero terminates it right away
+	pub close_grace: time::Duration,
+	// how many times to try killing processes before giving up, without
+	// prompting the user to retry
+	pub kill_attempts: u32,
+	// also kill the children of processes, and theirs
+	pub kill_tree: bool,
+}
+
+impl Default for WaitOptions {
+	fn default() -> WaitOptions {
+		WaitOptions {
+			strict: false,
+			timeout: time::Duration::from_secs(30),
+			close_grace: time::Duration::from_secs(5),
+			kill_attempts: 5,
+			kill_tree: false,
+		}
+	}
+}
+
+impl WaitOptions {
+	/**
+	 * The interval between process checks: a sixtieth of the timeout,
+	 * clamped between 50ms and 500ms.
+	 */
+	fn poll_interval(&self) -> time::Duration {
+		let interval = self.timeout / 60;
+		let min = time::Duration::from_millis(50);
+		let max = time::Duration::from_millis(500);
+
+		if interval < min {
+			min
+		} else if interval > max {
+			max
+		} else {
+			interval
+		}
 	}
 }
 
-pub fn wait_or_kill(log: &slog::Logger, path: &Path) -> Result<(), Box<dyn error::Error>> {
+fn process_file_name(path: &Path) -> Result<&str, io::Error> {
 	let file_name = path
 		.file_name()
 		.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Could not get process file name"))?;
 
-	let file_name = file_name.to_str().ok_or_else(|| {
+	file_name.to_str().ok_or_else(|| {
 		io::Error::new(
 			io::ErrorKind::Other,
 			"Could not get convert file name to str",
 		)
-	})?;
+	})
+}
 
+/**
+ * Waits up to `options.timeout` for all processes running `path` to exit
+ * on their own, and returns whether they did. Nothing gets killed.
+ */
+pub fn wait_until_exited(
+	log: &slog::Logger,
+	path: &Path,
+	options: &WaitOptions,
+) -> Result<bool, Box<dyn error::Error>> {
+	let file_name = process_file_name(path)?;
+	let wide_file_name: Vec<u16> = file_name.encode_utf16().collect();
+	let interval = options.poll_interval();
+	let deadline = time::Instant::now() + options.timeout;
 	let mut attempt: u32 = 0;
+	// processes a wait found to have exited
+	let mut exited: Vec<u32> = vec![];
+	// other users' processes can't be killed, so they aren't waited on either
+	let own_session = session_id(std::process::id());
+	let mut other_sessions: Vec<u32> = vec![];
 
-	// wait for 10 seconds until all processes are dead
+	// wait until all processes are dead, or the timeout elapses
 	loop {
 		attempt += 1;
 
@@ -181,26 +730,95 @@ pub fn wait_or_kill(log: &slog::Logger, path: &Path) -> Result<(), Box<dyn error
 			"Checking for running {} processes... (attempt {})", file_name, attempt
 		);
 
-		let process_found = get_running_processes()?
-			.into_iter()
-			.any(|p| p.name == file_name);
+		let (elsewhere, processes): (Vec<RunningProcess>, Vec<RunningProcess>) =
+			find_running_processes(|pe32| exe_name_matches(pe32, &wide_file_name))?
+				.into_iter()
+				.filter(|p| p.may_be_at(path))
+				.partition(|p| in_other_session(p.id, own_session));
+
+		for process in elsewhere {
+			if !other_sessions.contains(&process.id) {
+				info!(
+					log,
+					"Ignoring {}, pid {}: it runs in another session", process.name, process.id
+				);
+				other_sessions.push(process.id);
+			}
+		}
 
-		if !process_found {
+		if processes.is_empty() {
 			info!(log, "{} is not running", file_name);
-			break;
+			return Ok(true);
 		}
 
-		// give up after 60 * 500ms = 30 seconds
-		if attempt == 60 {
+		let now = time::Instant::now();
+
+		if now >= deadline {
 			info!(log, "Gave up waiting for {} to exit", file_name);
-			break;
+			return Ok(false);
 		}
 
 		info!(log, "{} is running, wait a bit", file_name);
-		thread::sleep(time::Duration::from_millis(500));
+
+		// exited processes may linger in the snapshot while something still
+		// holds a handle to them; poll rather than spin on those
+		let lingering = processes.iter().all(|p| exited.contains(&p.id));
+		let waited = if lingering {
+			None
+		} else {
+			wait_for_exit(&processes, deadline - now)
+		};
+
+		// either way, look again: new instances may have started meanwhile
+		match waited {
+			Some(true) => exited = processes.iter().map(|p| p.id).collect(),
+			Some(false) => {}
+			None => thread::sleep(cmp::min(interval, deadline - now)),
+		}
+	}
+}
+
+pub fn wait_or_kill(
+	log: &slog::Logger,
+	path: &Path,
+	options: &WaitOptions,
+	dry_run: bool,
+) -> Result<(), Box<dyn error::Error>> {
+	wait_or_kill_with(log, path, options, |process| {
+		if options.kill_tree {
+			kill_process_tree(log, process, path, options.close_grace, dry_run)
+		} else {
+			kill_process_if(log, process, path, options.close_grace, dry_run)
+		}
+	})
+}
+
+/**
+ * Like `wait_or_kill`, with `kill` killing each process still running.
+ */
+fn wait_or_kill_with<F>(
+	log: &slog::Logger,
+	path: &Path,
+	options: &WaitOptions,
+	kill: F,
+) -> Result<(), Box<dyn error::Error>>
+where
+	F: Fn(&RunningProcess) -> Result<bool, Box<dyn error::Error>>,
+{
+	let file_name = process_file_name(path)?;
+	let wide_file_name: Vec<u16> = file_name.encode_utf16().collect();
+	let own_session = session_id(std::process::id());
+
+	if !wait_until_exited(log, path, options)? && options.strict {
+		return Err(io::Error::new(
+			io::ErrorKind::TimedOut,
+			format!("Timed out waiting for {} to exit", file_name),
+		)
+		.into());
 	}
 
-	// try to kill any running processes
+	// try to kill any running processes; processes which survive a few
+	// attempts are likely protected, so there's no point in retrying more
 	util::retry(
 		"attempting to kill any running Code.exe processes",
 		|attempt| {
@@ -209,21 +827,533 @@ pub fn wait_or_kill(log: &slog::Logger, path: &Path) -> Result<(), Box<dyn error
 				"Checking for possible conflicting running processes... (attempt {})", attempt
 			);
 
-			let kill_errors: Vec<_> = get_running_processes()?
-				.into_iter()
-				.filter(|p| p.name == file_name)
-				.filter_map(|p| kill_process_if(log, &p, path).err())
-				.collect();
+			let failures: Vec<(RunningProcess, String)> =
+				find_running_processes(|pe32| exe_name_matches(pe32, &wide_file_name))?
+					.into_iter()
+					.filter(|p| p.may_be_at(path))
+					.filter(|p| !in_other_session(p.id, own_session))
+					.filter_map(|p| kill(&p).err().map(|err| (p, err.to_string())))
+					.collect();
 
-			for err in &kill_errors {
-				warn!(log, "Kill error {}", err);
+			for (process, err) in &failures {
+				warn!(
+					log,
+					"Failed to kill {}, pid {}: {}", process.name, process.id, err
+				);
 			}
 
-			match kill_errors.len() {
-				0 => Ok(()),
-				_ => Err(kill_errors.into_iter().nth(1).unwrap()),
+			if failures.is_empty() {
+				Ok(())
+			} else {
+				Err(KillError(failures).into())
 			}
 		},
-		None,
+		&util::RetryOptions {
+			max_attempts: options.kill_attempts,
+			interactive: false,
+			..Default::default()
+		},
 	)
+	.map_err(|err| {
+		warn!(
+			log,
+			"Gave up killing {} after {} attempts", file_name, options.kill_attempts
+		);
+
+		// the processes left running are what's worth reporting
+		if err.is::<KillError>() {
+			return err;
+		}
+
+		io::Error::other(format!(
+			"Could not terminate {} after {} attempts: {}",
+			file_name, options.kill_attempts, err
+		))
+		.into()
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::cell::{Cell, RefCell};
+	use std::process::{Child, Command, Stdio};
+	use std::{env, fs};
+
+	// how long the helper process sleeps for, in milliseconds
+	const HELPER_MILLIS: &str = "INNO_UPDATER_HELPER_MILLIS";
+	// set for a helper which starts a child helper first
+	const HELPER_CHILD: &str = "INNO_UPDATER_HELPER_CHILD";
+
+	fn logger() -> slog::Logger {
+		slog::Logger::root(slog::Discard, o!())
+	}
+
+	/**
+	 * Only does something when started by `spawn_helper`: sleeps, to stand
+	 * in for a running Code.exe, after starting a child if asked to.
+	 */
+	#[test]
+	#[ignore]
+	fn helper() {
+		let _child = env::var(HELPER_CHILD).ok().map(|_| {
+			Command::new(env::current_exe().unwrap())
+				.args(["--ignored", "--exact", "process::tests::helper"])
+				.env_remove(HELPER_CHILD)
+				.stdout(Stdio::null())
+				.stderr(Stdio::null())
+				.spawn()
+				.unwrap()
+		});
+
+		if let Ok(millis) = env::var(HELPER_MILLIS) {
+			thread::sleep(time::Duration::from_millis(millis.parse().unwrap()));
+		}
+	}
+
+	/**
+	 * A copy of the test executable, at a path of its own for the test
+	 * `name`. It sits next to the original rather than in the temp
+	 * directory, whose path may be a short 8.3 one.
+	 */
+	fn helper_path(name: &str) -> PathBuf {
+		let exe = env::current_exe().unwrap();
+		let dir =
+			exe.parent()
+				.unwrap()
+				.join(format!("inno-updater-{}-{}", name, std::process::id()));
+
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+
+		let path = dir.join(format!("{}.exe", name));
+		fs::copy(&exe, &path).unwrap();
+		path
+	}
+
+	/**
+	 * Runs the helper at `path` for `millis`.
+	 */
+	fn spawn_helper(path: &Path, millis: u64) -> Child {
+		Command::new(path)
+			.args(["--ignored", "--exact", "process::tests::helper"])
+			.env(HELPER_MILLIS, millis.to_string())
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.spawn()
+			.unwrap()
+	}
+
+	fn stop_helper(mut child: Child, path: &Path) {
+		let _ = child.kill();
+		let _ = child.wait();
+		let _ = fs::remove_dir_all(path.parent().unwrap());
+	}
+
+	#[test]
+	fn kill_errors_list_every_process() {
+		let err = KillError(vec![
+			(
+				RunningProcess {
+					name: String::from("Code.exe"),
+					id: 42,
+					path: Some(PathBuf::from("C:\\Code\\Code.exe")),
+				},
+				String::from("Failed to kill process: Access is denied."),
+			),
+			(
+				RunningProcess {
+					name: String::from("Code.exe"),
+					id: 43,
+					path: None,
+				},
+				String::from("Failed to open process: The parameter is incorrect."),
+			),
+		]);
+
+		assert_eq!(
+			err.to_string(),
+			"Could not terminate these processes, please close them: \
+			 Code.exe (pid 42, C:\\Code\\Code.exe): Failed to kill process: Access is denied.; \
+			 Code.exe (pid 43, unknown path): Failed to open process: The parameter is incorrect."
+		);
+	}
+
+	#[test]
+	fn access_denied_terminating_is_skipped() {
+		use windows_sys::Win32::Foundation::{ERROR_ACCESS_DENIED, ERROR_INVALID_HANDLE};
+
+		let process = RunningProcess {
+			name: String::from("Code.exe"),
+			id: 42,
+			path: Some(PathBuf::from("C:\\Code\\Code.exe")),
+		};
+		let message = || Ok(String::from("Access is denied."));
+
+		assert!(!terminate_failed(&logger(), &process, ERROR_ACCESS_DENIED, message()).unwrap());
+
+		let err =
+			terminate_failed(&logger(), &process, ERROR_INVALID_HANDLE, message()).unwrap_err();
+		assert_eq!(err.to_string(), "Failed to kill process: Access is denied.");
+	}
+
+	#[test]
+	fn kill_errors_collect_every_process() {
+		let path = helper_path("kill-errors");
+		let children = RefCell::new(vec![
+			spawn_helper(&path, 60_000),
+			spawn_helper(&path, 60_000),
+		]);
+		let mut ids: Vec<u32> = children.borrow().iter().map(|c| c.id()).collect();
+
+		let options = WaitOptions {
+			timeout: time::Duration::from_millis(100),
+			kill_attempts: 1,
+			..Default::default()
+		};
+
+		let result = wait_or_kill_with(&logger(), &path, &options, |process| {
+			// let the helper exit and be reaped first, so that it can't be
+			// opened anymore
+			let position = children.borrow().iter().position(|c| c.id() == process.id);
+
+			if let Some(i) = position {
+				let mut child = children.borrow_mut().remove(i);
+				child.kill().unwrap();
+				child.wait().unwrap();
+			}
+
+			kill_process_if(
+				&logger(),
+				process,
+				&path,
+				time::Duration::from_secs(0),
+				false,
+			)
+		});
+
+		for mut child in children.into_inner() {
+			let _ = child.kill();
+			let _ = child.wait();
+		}
+
+		let _ = fs::remove_dir_all(path.parent().unwrap());
+
+		let err = result.unwrap_err();
+		let KillError(mut failures) = *err.downcast::<KillError>().unwrap();
+		failures.sort_by_key(|(process, _)| process.id);
+		ids.sort();
+
+		assert_eq!(failures.iter().map(|(p, _)| p.id).collect::<Vec<_>>(), ids);
+
+		for (process, err) in &failures {
+			assert_eq!(process.name, "kill-errors.exe");
+			assert_eq!(process.path.as_ref(), Some(&path));
+			assert!(err.starts_with("Failed to open process"), "{}", err);
+		}
+	}
+
+	#[test]
+	fn unkillable_processes_fail_after_the_kill_attempts() {
+		let path = helper_path("unkillable");
+		let child = spawn_helper(&path, 60_000);
+		let attempts = Cell::new(0);
+
+		let options = WaitOptions {
+			timeout: time::Duration::from_millis(100),
+			kill_attempts: 3,
+			..Default::default()
+		};
+
+		// stands in for a protected process, which no attempt can kill
+		let result = wait_or_kill_with(&logger(), &path, &options, |_| {
+			attempts.set(attempts.get() + 1);
+			Err(io::Error::new(io::ErrorKind::PermissionDenied, "Access is denied.").into())
+		});
+
+		stop_helper(child, &path);
+
+		let err = result.unwrap_err();
+		assert_eq!(attempts.get(), 3);
+		assert!(err.is::<KillError>());
+		assert!(
+			err.to_string().starts_with(
+				"Could not terminate these processes, please close them: unkillable.exe"
+			),
+			"{}",
+			err
+		);
+	}
+
+	#[test]
+	fn other_sessions_are_told_apart() {
+		let pid = std::process::id();
+		let own = session_id(pid).unwrap();
+
+		assert!(!in_other_session(pid, Some(own)));
+		assert!(in_other_session(pid, Some(own + 1)));
+
+		// unknown sessions are assumed to be ours
+		assert!(!in_other_session(pid, None));
+		assert!(!in_other_session(u32::MAX - 3, Some(own + 1)));
+	}
+
+	#[test]
+	fn find_running_processes_visits_this_process() {
+		let pid = std::process::id();
+		let mut visited = 0;
+
+		let processes = find_running_processes(|pe32| {
+			visited += 1;
+			pe32.th32ProcessID == pid
+		})
+		.unwrap();
+
+		assert!(visited > 1);
+		assert_eq!(processes.len(), 1);
+		assert_eq!(processes[0].id, pid);
+	}
+
+	#[test]
+	fn strict_wait_fails_rather_than_kill() {
+		let path = helper_path("strict-wait");
+		let child = spawn_helper(&path, 60_000);
+
+		let options = WaitOptions {
+			strict: true,
+			timeout: time::Duration::from_millis(500),
+			..Default::default()
+		};
+
+		let result = wait_or_kill(&logger(), &path, &options, false);
+		let still_running = find_running_processes(|pe32| pe32.th32ProcessID == child.id())
+			.unwrap()
+			.len();
+
+		stop_helper(child, &path);
+
+		let err = result.unwrap_err();
+		assert!(err.to_string().contains("Timed out"), "{}", err);
+		assert_eq!(
+			err.downcast_ref::<io::Error>().map(|err| err.kind()),
+			Some(io::ErrorKind::TimedOut)
+		);
+		assert_eq!(still_running, 1);
+	}
+
+	#[test]
+	fn short_wait_timeouts_return_promptly() {
+		let path = helper_path("wait-timeout");
+		let child = spawn_helper(&path, 60_000);
+
+		let options = WaitOptions {
+			timeout: time::Duration::from_millis(300),
+			..Default::default()
+		};
+
+		let started = time::Instant::now();
+		let result = wait_until_exited(&logger(), &path, &options);
+		let elapsed = started.elapsed();
+
+		stop_helper(child, &path);
+
+		assert!(!result.unwrap());
+		assert!(elapsed < time::Duration::from_secs(5), "{:?}", elapsed);
+	}
+
+	#[test]
+	fn waiting_only_returns_once_the_helper_exits() {
+		let path = helper_path("wait-only");
+		let mut child = spawn_helper(&path, 1000);
+		let reaper = thread::spawn(move || child.wait().unwrap());
+
+		let options = WaitOptions {
+			timeout: time::Duration::from_secs(30),
+			..Default::default()
+		};
+
+		let started = time::Instant::now();
+		let result = wait_until_exited(&logger(), &path, &options);
+		let elapsed = started.elapsed();
+
+		// the helper was reaped, so it has exited by now
+		reaper.join().unwrap();
+		let _ = fs::remove_dir_all(path.parent().unwrap());
+
+		assert!(result.unwrap());
+		assert!(elapsed >= time::Duration::from_millis(500), "{:?}", elapsed);
+		assert!(elapsed < time::Duration::from_secs(20), "{:?}", elapsed);
+	}
+
+	#[test]
+	fn running_processes_report_their_path() {
+		let path = helper_path("process-path");
+		let child = spawn_helper(&path, 60_000);
+		let pid = child.id();
+
+		let processes = find_running_processes(|pe32| pe32.th32ProcessID == pid).unwrap();
+		stop_helper(child, &path);
+
+		assert_eq!(processes.len(), 1);
+		assert_eq!(processes[0].name, "process-path.exe");
+		assert_eq!(processes[0].path.as_ref(), Some(&path));
+	}
+
+	#[test]
+	fn unreadable_module_paths_are_skipped() {
+		let path = helper_path("module-path");
+		let mut child = spawn_helper(&path, 0);
+
+		// an exited process can still be opened while `child` holds on to
+		// it, but it has no modules left to read a path from
+		child.wait().unwrap();
+
+		let process = RunningProcess {
+			name: String::from("module-path.exe"),
+			id: child.id(),
+			path: None,
+		};
+
+		let result = kill_process_if(
+			&logger(),
+			&process,
+			&path,
+			time::Duration::from_secs(0),
+			false,
+		);
+		stop_helper(child, &path);
+
+		assert!(!result.unwrap());
+	}
+
+	#[test]
+	fn wait_for_exit_wakes_up_when_processes_exit() {
+		let path = helper_path("wait-for-exit");
+		let mut child = spawn_helper(&path, 500);
+		let pid = child.id();
+		let processes = find_running_processes(|pe32| pe32.th32ProcessID == pid).unwrap();
+
+		// reap the helper as soon as it exits, so nothing holds on to it
+		let reaper = thread::spawn(move || child.wait().unwrap());
+
+		let started = time::Instant::now();
+		let exited = wait_for_exit(&processes, time::Duration::from_secs(30));
+		let elapsed = started.elapsed();
+
+		reaper.join().unwrap();
+		let _ = fs::remove_dir_all(path.parent().unwrap());
+
+		assert_eq!(processes.len(), 1);
+		assert_eq!(exited, Some(true));
+		assert!(elapsed < time::Duration::from_secs(10), "{:?}", elapsed);
+	}
+
+	#[test]
+	fn kill_process_tree_kills_children_too() {
+		let path = helper_path("kill-tree");
+		let mut child = Command::new(&path)
+			.args(["--ignored", "--exact", "process::tests::helper"])
+			.env(HELPER_MILLIS, "60000")
+			.env(HELPER_CHILD, "1")
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.spawn()
+			.unwrap();
+		let pid = child.id();
+
+		let started = time::Instant::now();
+		let mut descendants = vec![];
+
+		while descendants.is_empty() && started.elapsed() < time::Duration::from_secs(10) {
+			thread::sleep(time::Duration::from_millis(50));
+			descendants = find_descendants(pid).unwrap();
+		}
+
+		let process = find_running_processes(|pe32| pe32.th32ProcessID == pid)
+			.unwrap()
+			.pop()
+			.unwrap();
+		let result = kill_process_tree(
+			&logger(),
+			&process,
+			&path,
+			time::Duration::from_secs(0),
+			false,
+		);
+
+		// reap the helper, so that it doesn't linger in the snapshot
+		let _ = child.wait();
+		drop(child);
+
+		let mut left = descendants.clone();
+
+		while !left.is_empty() && started.elapsed() < time::Duration::from_secs(20) {
+			thread::sleep(time::Duration::from_millis(50));
+			left = find_running_processes(|pe32| descendants.contains(&pe32.th32ProcessID))
+				.unwrap()
+				.into_iter()
+				.map(|p| p.id)
+				.collect();
+		}
+
+		let _ = fs::remove_dir_all(path.parent().unwrap());
+
+		assert!(result.unwrap());
+		assert_eq!(descendants.len(), 1);
+		assert!(left.is_empty(), "{:?} still running", left);
+	}
+
+	#[test]
+	fn kill_process_tree_leaves_other_paths_alone() {
+		let path = helper_path("kill-tree-other");
+		let mut child = Command::new(&path)
+			.args(["--ignored", "--exact", "process::tests::helper"])
+			.env(HELPER_MILLIS, "60000")
+			.env(HELPER_CHILD, "1")
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.spawn()
+			.unwrap();
+		let pid = child.id();
+
+		let started = time::Instant::now();
+		let mut descendants = vec![];
+
+		while descendants.is_empty() && started.elapsed() < time::Duration::from_secs(10) {
+			thread::sleep(time::Duration::from_millis(50));
+			descendants = find_descendants(pid).unwrap();
+		}
+
+		let process = find_running_processes(|pe32| pe32.th32ProcessID == pid)
+			.unwrap()
+			.pop()
+			.unwrap();
+
+		// a same-named process of another installation
+		let result = kill_process_tree(
+			&logger(),
+			&process,
+			&path.with_file_name("Code.exe"),
+			time::Duration::from_secs(0),
+			false,
+		);
+
+		let running = find_running_processes(|pe32| {
+			pe32.th32ProcessID == pid || descendants.contains(&pe32.th32ProcessID)
+		})
+		.unwrap()
+		.len();
+
+		for &descendant in &descendants {
+			let _ = terminate_process(&logger(), descendant, false);
+		}
+
+		let _ = child.kill();
+		let _ = child.wait();
+		let _ = fs::remove_dir_all(path.parent().unwrap());
+
+		assert!(!result.unwrap());
+		assert_eq!(descendants.len(), 1);
+		assert_eq!(running, 2);
+	}
 }
@@ -9,3 +9,11 @@ pub const ICON_CODE: u16 = 101;
 pub const PROGRESS_DIALOG: u16 = 1001;
 
 pub const PROGRESS_SLIDER: i32 = 10001;
+
+pub const PROGRESS_DETAIL: i32 = 10004;
+
+pub const FAILURE_DIALOG: u16 = 1002;
+
+pub const FAILURE_TEXT: i32 = 10002;
+
+pub const FAILURE_OPEN_LOG: i32 = 10003;
@@ -24,7 +24,7 @@ pub fn read_utf8_string(
 		.read_exact(&mut vec)
 		.map_err(ReadUtf8StringError::IOError)
 		.and_then(|_| {
-			let pos = vec.iter().position(|&x| x == 0).unwrap_or(64);
+			let pos = vec.iter().position(|&x| x == 0).unwrap_or(capacity);
 			let bar = &vec[0..pos];
 			String::from_utf8(Vec::from(bar)).map_err(ReadUtf8StringError::UTF8Error)
 		})
@@ -36,6 +36,18 @@ pub fn write_utf8_string(
 	capacity: usize,
 ) -> Result<(), io::Error> {
 	let bytes = string.as_bytes();
+
+	if bytes.len() > capacity {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			format!(
+				"String is {} bytes long, but only {} fit",
+				bytes.len(),
+				capacity
+			),
+		));
+	}
+
 	writer.write_all(bytes)?;
 
 	let rest = vec![0; capacity - bytes.len()];
@@ -62,7 +74,119 @@ pub fn from_utf16(value: &[u16]) -> Result<String, io::Error> {
 		.map_err(|_| io::Error::new(io::ErrorKind::Other, "Could not convert from utf16"))
 }
 
+/**
+ * Quotes and escapes a string for use as a JSON string literal.
+ */
+pub fn to_json_string(value: &str) -> String {
+	let mut result = String::with_capacity(value.len() + 2);
+	result.push('"');
+
+	for c in value.chars() {
+		match c {
+			'"' => result.push_str("\\\""),
+			'\\' => result.push_str("\\\\"),
+			'\n' => result.push_str("\\n"),
+			'\r' => result.push_str("\\r"),
+			'\t' => result.push_str("\\t"),
+			c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+			c => result.push(c),
+		}
+	}
+
+	result.push('"');
+	result
+}
+
+/**
+ * Strips the directory `prefix` off `path`, ignoring case as Windows does,
+ * and returns the rest, which is either empty or starts with a backslash.
+ * Only whole components match, so `C:\a` is no prefix of `C:\ab`.
+ */
+pub fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+	let prefix = prefix.trim_end_matches('\\');
+
+	match (path.get(..prefix.len()), path.get(prefix.len()..)) {
+		(Some(head), Some(rest))
+			if head.eq_ignore_ascii_case(prefix) && (rest.is_empty() || rest.starts_with('\\')) =>
+		{
+			Some(rest)
+		}
+		_ => None,
+	}
+}
+
 pub fn to_u16s<S: AsRef<OsStr>>(s: S) -> Vec<u16> {
 	use std::iter::once;
 	s.as_ref().encode_wide().chain(once(0u16)).collect()
 }
+
+// Windows-1252 characters for bytes 0x80 to 0x9f; unassigned bytes map to
+// the matching C1 control character, as MultiByteToWideChar does
+const WINDOWS_1252_C1: [u16; 32] = [
+	0x20ac, 0x0081, 0x201a, 0x0192, 0x201e, 0x2026, 0x2020, 0x2021, 0x02c6, 0x2030, 0x0160, 0x2039,
+	0x0152, 0x008d, 0x017d, 0x008f, 0x0090, 0x2018, 0x2019, 0x201c, 0x201d, 0x2022, 0x2013, 0x2014,
+	0x02dc, 0x2122, 0x0161, 0x203a, 0x0153, 0x009d, 0x017e, 0x0178,
+];
+
+pub fn decode_windows_1252(bytes: &[u8]) -> String {
+	bytes
+		.iter()
+		.map(|&b| match b {
+			0x80..=0x9f => {
+				char::from_u32(WINDOWS_1252_C1[(b - 0x80) as usize] as u32).unwrap_or('\u{fffd}')
+			}
+			_ => b as char,
+		})
+		.collect()
+}
+
+pub fn encode_windows_1252(value: &str) -> Option<Vec<u8>> {
+	value
+		.chars()
+		.map(|c| match c as u32 {
+			x @ 0x00..=0x7f | x @ 0xa0..=0xff => Some(x as u8),
+			x => WINDOWS_1252_C1
+				.iter()
+				.position(|&w| w as u32 == x)
+				.map(|i| 0x80 + i as u8),
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn strip_path_prefix_matches_whole_components_in_any_case() {
+		assert_eq!(strip_path_prefix("C:\\a\\b", "C:\\a"), Some("\\b"));
+		assert_eq!(strip_path_prefix("C:\\a\\b", "c:\\A\\"), Some("\\b"));
+		assert_eq!(strip_path_prefix("C:\\a", "C:\\a"), Some(""));
+		assert_eq!(strip_path_prefix("C:\\ab", "C:\\a"), None);
+		assert_eq!(strip_path_prefix("C:\\b\\a", "C:\\a"), None);
+		assert_eq!(strip_path_prefix("C:", "C:\\a"), None);
+	}
+
+	#[test]
+	fn full_fields_are_read_without_a_nul() {
+		let field = "x".repeat(128);
+		let string = read_utf8_string(&mut field.as_bytes(), 128).unwrap();
+		assert_eq!(string, field);
+
+		let mut padded = b"Code".to_vec();
+		padded.resize(128, 0);
+		assert_eq!(read_utf8_string(&mut &padded[..], 128).unwrap(), "Code");
+	}
+
+	#[test]
+	fn over_long_strings_are_not_written() {
+		let mut output = vec![];
+		let err = write_utf8_string(&mut output, &"x".repeat(129), 128).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+		assert!(output.is_empty());
+
+		write_utf8_string(&mut output, &String::from("Code"), 128).unwrap();
+		assert_eq!(output.len(), 128);
+		assert_eq!(read_utf8_string(&mut &output[..], 128).unwrap(), "Code");
+	}
+}
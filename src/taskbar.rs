@@ -0,0 +1,154 @@
+/*-----------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *----------------------------------------------------------------------------------------*/
+
+use std::cell::Cell;
+use std::ptr;
+use strings::to_utf16;
+use windows_sys::core::{GUID, HRESULT};
+use windows_sys::Win32::Foundation::HWND;
+
+// windows-sys has no COM vtables, so this lays out ITaskbarList3 up to the
+// last method used here; unused slots are never called
+#[repr(C)]
+struct TaskbarListVtbl {
+	query_interface: usize,
+	add_ref: usize,
+	release: unsafe extern "system" fn(*mut TaskbarListObj) -> u32,
+	hr_init: unsafe extern "system" fn(*mut TaskbarListObj) -> HRESULT,
+	add_tab: usize,
+	delete_tab: usize,
+	activate_tab: usize,
+	set_active_alt: usize,
+	mark_fullscreen_window: usize,
+	set_progress_value: unsafe extern "system" fn(*mut TaskbarListObj, HWND, u64, u64) -> HRESULT,
+	set_progress_state: unsafe extern "system" fn(*mut TaskbarListObj, HWND, i32) -> HRESULT,
+}
+
+#[repr(C)]
+struct TaskbarListObj {
+	vtbl: *const TaskbarListVtbl,
+}
+
+const IID_ITASKBARLIST3: GUID = GUID {
+	data1: 0xea1afb91,
+	data2: 0x9e28,
+	data3: 0x4b86,
+	data4: [0x90, 0xe9, 0x9e, 0x9f, 0x8a, 0x5e, 0xef, 0xaf],
+};
+
+/**
+ * Mirrors the progress of a window on its taskbar button. It must be used
+ * on the thread which created it, with COM initialized, and every call is
+ * best effort: the update never depends on the taskbar.
+ */
+pub struct Taskbar {
+	list: *mut TaskbarListObj,
+	hwnd: HWND,
+	// the message Explorer sends once the button exists, and again if it
+	// restarts; progress set before then is lost, so it gets applied again
+	button_created: u32,
+	progress: Cell<Option<u8>>,
+}
+
+impl Taskbar {
+	pub fn new(hwnd: HWND) -> Option<Taskbar> {
+		use windows_sys::Win32::System::Com::{CoCreateInstance, CLSCTX_INPROC_SERVER};
+		use windows_sys::Win32::UI::Shell::TaskbarList;
+		use windows_sys::Win32::UI::WindowsAndMessaging::{
+			ChangeWindowMessageFilterEx, RegisterWindowMessageW, MSGFLT_ALLOW,
+		};
+
+		unsafe {
+			let mut list: *mut TaskbarListObj = ptr::null_mut();
+			let result = CoCreateInstance(
+				&TaskbarList,
+				ptr::null_mut(),
+				CLSCTX_INPROC_SERVER,
+				&IID_ITASKBARLIST3,
+				&mut list as *mut *mut TaskbarListObj as *mut _,
+			);
+
+			if result < 0 || list.is_null() {
+				return None;
+			}
+
+			if ((*(*list).vtbl).hr_init)(list) < 0 {
+				((*(*list).vtbl).release)(list);
+				return None;
+			}
+
+			let button_created = RegisterWindowMessageW(to_utf16("TaskbarButtonCreated").as_ptr());
+
+			// an elevated updater doesn't get the message from Explorer otherwise
+			ChangeWindowMessageFilterEx(hwnd, button_created, MSGFLT_ALLOW, ptr::null_mut());
+
+			Some(Taskbar {
+				list,
+				hwnd,
+				button_created,
+				progress: Cell::new(None),
+			})
+		}
+	}
+
+	pub fn is_button_created(&self, msg: u32) -> bool {
+		msg != 0 && msg == self.button_created
+	}
+
+	/**
+	 * Shows `percent` on the taskbar button, or an indeterminate state for
+	 * `None`.
+	 */
+	pub fn set_progress(&self, percent: Option<u8>) {
+		self.progress.set(percent);
+		self.apply();
+	}
+
+	/**
+	 * Shows the last progress set again.
+	 */
+	pub fn apply(&self) {
+		use windows_sys::Win32::UI::Shell::TBPF_INDETERMINATE;
+
+		unsafe {
+			match self.progress.get() {
+				Some(percent) => {
+					((*(*self.list).vtbl).set_progress_value)(
+						self.list,
+						self.hwnd,
+						percent as u64,
+						100,
+					);
+				}
+				None => {
+					((*(*self.list).vtbl).set_progress_state)(
+						self.list,
+						self.hwnd,
+						TBPF_INDETERMINATE,
+					);
+				}
+			}
+		}
+	}
+
+	/**
+	 * Removes any progress from the taskbar button.
+	 */
+	pub fn clear(&self) {
+		use windows_sys::Win32::UI::Shell::TBPF_NOPROGRESS;
+
+		unsafe {
+			((*(*self.list).vtbl).set_progress_state)(self.list, self.hwnd, TBPF_NOPROGRESS);
+		}
+	}
+}
+
+impl Drop for Taskbar {
+	fn drop(&mut self) {
+		unsafe {
+			((*(*self.list).vtbl).release)(self.list);
+		}
+	}
+}
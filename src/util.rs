@@ -3,60 +3,203 @@
  *  Licensed under the MIT License. See LICENSE in the project root for license information.
  *----------------------------------------------------------------------------------------*/
 
+use blockio::CRC32;
 use gui;
-use std::{error, ptr, thread, time};
+use messages::t;
+use std::io::prelude::*;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::{cmp, error, fmt, fs, io, ptr, thread, time};
 use strings::from_utf16;
 
+#[derive(Debug, Clone)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Operation was cancelled")
+	}
+}
+
+impl error::Error for Cancelled {
+	fn description(&self) -> &str {
+		"Cancelled"
+	}
+
+	fn cause(&self) -> Option<&dyn error::Error> {
+		None
+	}
+}
+
+/**
+ * How `retry` goes about retrying.
+ */
+#[derive(Clone, Copy)]
+pub struct RetryOptions<'a> {
+	// attempts before giving up, or asking whether to go on
+	pub max_attempts: u32,
+	// the wait after each failed attempt
+	pub backoff: fn(u32) -> time::Duration,
+	// once `max_attempts` is reached, ask with `ask` whether to go on,
+	// rather than returning the last error
+	pub interactive: bool,
+	// asks the user whether to retry, given what failed
+	pub ask: fn(&str) -> bool,
+	// errors this rejects are returned right away, without another attempt
+	pub retryable: fn(&(dyn error::Error + 'static)) -> bool,
+	// give up with a `Cancelled` error as soon as this is set, even in the
+	// middle of a backoff
+	pub cancel: Option<&'a Arc<AtomicBool>>,
+}
+
+impl<'a> Default for RetryOptions<'a> {
+	fn default() -> RetryOptions<'a> {
+		RetryOptions {
+			max_attempts: 11,
+			backoff: quadratic_backoff,
+			interactive: true,
+			ask: ask_with_message_box,
+			retryable: |_| true,
+			cancel: None,
+		}
+	}
+}
+
 /**
  * Quadratic backoff retry mechanism.
  *
- * Use `max_attempts` to control how long it should retry for:
- * 	- 11 (default): 19s
+ * Use `options.max_attempts` to control how long it should retry for:
+ *  - 11 (default): 19s
  *  - 16: ~1 minute
  *  - 20: ~2 minutes
  *  - 23: ~3 minutes
  *  - 25: ~4 minutes
  *  - 27: ~5 minutes
+ *
+ * Once `max_attempts` is reached, a message box offers to retry again,
+ * unless `options.interactive` is false: then the last error is returned.
  */
-pub fn retry<F, R, T>(task: &str, closure: F, max_attempts: T) -> Result<R, Box<dyn error::Error>>
+pub fn retry<F, R>(
+	task: &str,
+	closure: F,
+	options: &RetryOptions,
+) -> Result<R, Box<dyn error::Error>>
 where
 	F: Fn(u32) -> Result<R, Box<dyn error::Error>>,
-	T: Into<Option<u32>>,
 {
 	let mut attempt: u32 = 0;
-	let max_attempts = max_attempts.into().unwrap_or(11);
+	let is_cancelled = || options.cancel.is_some_and(|c| c.load(Ordering::SeqCst));
 
 	loop {
+		if is_cancelled() {
+			return Err(Cancelled.into());
+		}
+
 		attempt += 1;
 
 		let result = closure(attempt);
 		match result {
 			Ok(_) => return result,
 			Err(err) => {
-				if attempt >= max_attempts {
-					let msg = format!("There was an error while {}:\n\n{}\n\nPlease verify there are no Visual Studio Code processes still executing.", task, err);
-					let mb_result = gui::message_box(
-						&msg,
-						"Visual Studio Code",
-						gui::MessageBoxType::RetryCancel,
-					);
-
-					match mb_result {
-						gui::MessageBoxResult::Retry => {
-							attempt = 0;
-						}
-						_ => {
-							return Err(err);
-						}
+				if !(options.retryable)(err.as_ref()) {
+					return Err(err);
+				}
+
+				if attempt >= options.max_attempts {
+					if !options.interactive {
+						return Err(err);
+					}
+
+					let msg = t("retry-failed")
+						.replace("{task}", task)
+						.replace("{err}", &err.to_string());
+
+					if !(options.ask)(&msg) {
+						return Err(err);
 					}
+
+					attempt = 0;
 				}
 
-				thread::sleep(time::Duration::from_millis((attempt.pow(2) * 50) as u64));
+				let deadline = time::Instant::now() + (options.backoff)(attempt);
+
+				// sleep in small slices, so that cancellation is noticed promptly
+				while !is_cancelled() {
+					let now = time::Instant::now();
+
+					if now >= deadline {
+						break;
+					}
+
+					thread::sleep(cmp::min(deadline - now, time::Duration::from_millis(50)));
+				}
 			}
 		}
 	}
 }
 
+/**
+ * The way `retry` asks by default: with a Retry/Cancel message box.
+ */
+fn ask_with_message_box(msg: &str) -> bool {
+	matches!(
+		gui::message_box(msg, &t("product"), gui::MessageBoxType::RetryCancel),
+		gui::MessageBoxResult::Retry
+	)
+}
+
+/**
+ * The backoff `retry` uses by default: 50ms times the square of the
+ * attempt.
+ */
+pub fn quadratic_backoff(attempt: u32) -> time::Duration {
+	time::Duration::from_millis((attempt.pow(2) * 50) as u64)
+}
+
+// how much of a file `hash_file` reads between progress reports
+const HASH_CHUNK_SIZE: usize = 1024 * 1024;
+
+/**
+ * Computes the CRC32 of the file at `path`, a chunk at a time, calling
+ * `progress(hashed, total)` in between chunks so that the caller can keep
+ * its UI going. Gives up with a `Cancelled` error as soon as `cancel` is
+ * set.
+ */
+pub fn hash_file<F>(
+	path: &Path,
+	mut progress: F,
+	cancel: Option<&Arc<AtomicBool>>,
+) -> Result<u32, Box<dyn error::Error>>
+where
+	F: FnMut(u64, u64),
+{
+	let mut file = fs::File::open(path)?;
+	let total = file.metadata()?.len();
+	let mut digest = CRC32.digest();
+	let mut buffer = vec![0; HASH_CHUNK_SIZE];
+	let mut hashed: u64 = 0;
+
+	loop {
+		if cancel.is_some_and(|c| c.load(Ordering::SeqCst)) {
+			return Err(Cancelled.into());
+		}
+
+		let count = match file.read(&mut buffer) {
+			Ok(0) => break,
+			Ok(count) => count,
+			Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+			Err(err) => return Err(err.into()),
+		};
+
+		digest.update(&buffer[..count]);
+		hashed += count as u64;
+		progress(hashed, total);
+	}
+
+	Ok(digest.finalize())
+}
+
 pub fn get_last_error_message() -> Result<String, Box<dyn error::Error>> {
 	use windows_sys::Win32::Foundation::GetLastError;
 	use windows_sys::Win32::System::Diagnostics::Debug::{
@@ -83,3 +226,134 @@ pub fn get_last_error_message() -> Result<String, Box<dyn error::Error>> {
 		_ => from_utf16(&error_message[0..error_message_len])?,
 	})
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn failing(_: u32) -> Result<(), Box<dyn error::Error>> {
+		Err(io::Error::other("still failing").into())
+	}
+
+	#[test]
+	fn retry_returns_cancelled_mid_backoff() {
+		let cancel = Arc::new(AtomicBool::new(false));
+		let setter = {
+			let cancel = cancel.clone();
+			thread::spawn(move || {
+				thread::sleep(time::Duration::from_millis(100));
+				cancel.store(true, Ordering::SeqCst);
+			})
+		};
+
+		let started = time::Instant::now();
+		let result = retry(
+			"failing forever",
+			failing,
+			&RetryOptions {
+				max_attempts: 100,
+				backoff: |_| time::Duration::from_secs(60),
+				interactive: false,
+				cancel: Some(&cancel),
+				..Default::default()
+			},
+		);
+
+		setter.join().unwrap();
+		assert!(result.unwrap_err().is::<Cancelled>());
+		assert!(started.elapsed() < time::Duration::from_secs(5));
+	}
+
+	#[test]
+	fn quadratic_backoff_grows_with_the_square_of_the_attempt() {
+		let delays: Vec<u64> = (1..=4)
+			.map(|attempt| quadratic_backoff(attempt).as_millis() as u64)
+			.collect();
+
+		assert_eq!(delays, vec![50, 200, 450, 800]);
+		assert_eq!(quadratic_backoff(10), time::Duration::from_secs(5));
+	}
+
+	#[test]
+	fn non_interactive_retry_gives_up_after_max_attempts() {
+		let attempts = std::cell::Cell::new(0);
+		let result = retry(
+			"failing forever",
+			|attempt| {
+				attempts.set(attempt);
+				failing(attempt)
+			},
+			&RetryOptions {
+				max_attempts: 3,
+				backoff: |_| time::Duration::from_millis(0),
+				interactive: false,
+				..Default::default()
+			},
+		);
+
+		assert!(result.is_err());
+		assert_eq!(attempts.get(), 3);
+	}
+
+	#[test]
+	fn hash_file_matches_hashing_in_one_go() {
+		let path = std::env::temp_dir().join(format!("inno-updater-hash-{}", std::process::id()));
+		let data: Vec<u8> = (0..5 * HASH_CHUNK_SIZE + 123)
+			.map(|i| (i % 251) as u8)
+			.collect();
+		fs::write(&path, &data).unwrap();
+
+		let mut reported = vec![];
+		let hash = hash_file(&path, |hashed, total| reported.push((hashed, total)), None);
+
+		// cancelled from the first progress report on
+		let cancel = Arc::new(AtomicBool::new(false));
+		let mut calls = 0;
+		let cancelled = hash_file(
+			&path,
+			|_, _| {
+				calls += 1;
+				cancel.store(true, Ordering::SeqCst);
+			},
+			Some(&cancel),
+		);
+
+		fs::remove_file(&path).unwrap();
+
+		assert_eq!(hash.unwrap(), CRC32.checksum(&data));
+		assert_eq!(reported.len(), 6);
+		assert_eq!(
+			reported.last(),
+			Some(&(data.len() as u64, data.len() as u64))
+		);
+		assert!(cancelled.unwrap_err().is::<Cancelled>());
+		assert_eq!(calls, 1);
+	}
+
+	#[test]
+	fn only_interactive_retries_ask() {
+		let options = |interactive: bool| RetryOptions {
+			max_attempts: 2,
+			backoff: |_| time::Duration::from_millis(0),
+			interactive,
+			ask: |_| panic!("asked whether to retry"),
+			..Default::default()
+		};
+
+		assert!(retry("failing forever", failing, &options(false)).is_err());
+
+		let asked = std::panic::catch_unwind(|| retry("failing forever", failing, &options(true)));
+		assert!(asked.is_err());
+
+		// declining gives up with the last error
+		let declined = retry(
+			"failing forever",
+			failing,
+			&RetryOptions {
+				ask: |_| false,
+				..options(true)
+			},
+		);
+		assert_eq!(declined.unwrap_err().to_string(), "still failing");
+	}
+}